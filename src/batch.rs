@@ -0,0 +1,49 @@
+use crate::record::Record;
+
+/// A group of sets and removes applied atomically by `KvStore::write`
+///
+/// Either all operations of a batch survive a crash or none of them do.
+/// Operations are applied in the order they were added.
+#[derive(Debug, Default)]
+pub struct WriteBatch {
+    records: Vec<Record>,
+}
+
+impl WriteBatch {
+    /// Creates an empty batch
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Adds setting the value of a key
+    pub fn set<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(&mut self, key: K, value: V) -> &mut WriteBatch {
+        self.records.push(Record::SetRecord {
+            key: key.into(),
+            value: value.into(),
+            expires_at: None,
+        });
+        self
+    }
+
+    /// Adds removing a key
+    ///
+    /// Unlike `KvStore::remove`, removing a key which does not exist is not an error.
+    pub fn remove<K: Into<Vec<u8>>>(&mut self, key: K) -> &mut WriteBatch {
+        self.records.push(Record::RemoveRecord { key: key.into() });
+        self
+    }
+
+    /// Returns the number of operations in the batch
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Returns `true` if the batch contains no operations
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub(crate) fn into_records(self) -> Vec<Record> {
+        self.records
+    }
+}
@@ -0,0 +1,156 @@
+extern crate clap;
+
+use clap::{App, AppSettings, Arg, SubCommand};
+use kvs::{KvsClient, KvsError, Result};
+use std::env;
+use std::process::exit;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+
+fn main() -> Result<()> {
+    let addr_arg = Arg::with_name("addr")
+        .long("addr")
+        .value_name("IP-PORT")
+        .default_value(DEFAULT_ADDR);
+    let matches = App::new(env!("CARGO_PKG_NAME"))
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about(env!("CARGO_PKG_DESCRIPTION"))
+        .setting(AppSettings::ArgRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("set")
+                .about("Set key value in storage")
+                .arg(
+                    Arg::with_name("key")
+                        .index(1)
+                        .value_name("KEY")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("value")
+                        .index(2)
+                        .value_name("VALUE")
+                        .required(true),
+                )
+                .arg(addr_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("get")
+                .about("Get value for given key")
+                .arg(
+                    Arg::with_name("key")
+                        .index(1)
+                        .value_name("KEY")
+                        .required(true),
+                )
+                .arg(addr_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("rm")
+                .about("Remove given key")
+                .arg(
+                    Arg::with_name("key")
+                        .index(1)
+                        .value_name("KEY")
+                        .required(true),
+                )
+                .arg(addr_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("cas")
+                .about("Set key to a new value only if it has the expected value")
+                .arg(
+                    Arg::with_name("key")
+                        .index(1)
+                        .value_name("KEY")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("expected")
+                        .long("expected")
+                        .value_name("VALUE")
+                        .help("Value the key must have, the key must not exist if omitted"),
+                )
+                .arg(
+                    Arg::with_name("new")
+                        .long("new")
+                        .value_name("VALUE")
+                        .help("Value to set, the key is removed if omitted"),
+                )
+                .arg(addr_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("set-if-absent")
+                .about("Set key value only if the key does not exist")
+                .arg(
+                    Arg::with_name("key")
+                        .index(1)
+                        .value_name("KEY")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("value")
+                        .index(2)
+                        .value_name("VALUE")
+                        .required(true),
+                )
+                .arg(addr_arg),
+        )
+        .get_matches();
+
+    match matches.subcommand() {
+        ("set", Some(matches)) => {
+            let key = matches.value_of("key").expect("key argument missing");
+            let value = matches.value_of("value").expect("value argument missing");
+            let addr = matches.value_of("addr").expect("addr argument missing");
+            let mut client = KvsClient::connect(addr)?;
+            client.set(key.to_string(), value.to_string())?;
+        }
+        ("get", Some(matches)) => {
+            let key = matches.value_of("key").expect("key argument missing");
+            let addr = matches.value_of("addr").expect("addr argument missing");
+            let mut client = KvsClient::connect(addr)?;
+            if let Some(value) = client.get(key.to_string())? {
+                println!("{}", value);
+            } else {
+                println!("Key not found");
+            }
+        }
+        ("rm", Some(matches)) => {
+            let key = matches.value_of("key").expect("key argument missing");
+            let addr = matches.value_of("addr").expect("addr argument missing");
+            let mut client = KvsClient::connect(addr)?;
+            match client.remove(key.to_string()) {
+                Ok(_) => {}
+                Err(KvsError::KeyNotFound) => {
+                    eprintln!("Key not found");
+                    exit(1);
+                }
+                Err(why) => return Err(why),
+            }
+        }
+        ("cas", Some(matches)) => {
+            let key = matches.value_of("key").expect("key argument missing");
+            let expected = matches.value_of("expected").map(str::to_string);
+            let new = matches.value_of("new").map(str::to_string);
+            let addr = matches.value_of("addr").expect("addr argument missing");
+            let mut client = KvsClient::connect(addr)?;
+            if !client.compare_and_swap(key.to_string(), expected, new)? {
+                eprintln!("Value does not match");
+                exit(1);
+            }
+        }
+        ("set-if-absent", Some(matches)) => {
+            let key = matches.value_of("key").expect("key argument missing");
+            let value = matches.value_of("value").expect("value argument missing");
+            let addr = matches.value_of("addr").expect("addr argument missing");
+            let mut client = KvsClient::connect(addr)?;
+            if !client.set_if_absent(key.to_string(), value.to_string())? {
+                eprintln!("Key already exists");
+                exit(1);
+            }
+        }
+        _ => unreachable!(),
+    };
+    Ok(())
+}
@@ -0,0 +1,40 @@
+extern crate clap;
+
+use clap::{App, Arg};
+use kvs::{KvStore, KvsError, KvsServer, Result};
+use log::{error, info, LevelFilter};
+use std::env;
+use std::env::current_dir;
+use std::process::exit;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+
+fn main() -> Result<()> {
+    env_logger::builder().filter_level(LevelFilter::Info).init();
+    let matches = App::new(env!("CARGO_PKG_NAME"))
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about(env!("CARGO_PKG_DESCRIPTION"))
+        .arg(
+            Arg::with_name("addr")
+                .long("addr")
+                .value_name("IP-PORT")
+                .default_value(DEFAULT_ADDR),
+        )
+        .get_matches();
+
+    let addr = matches.value_of("addr").expect("addr argument missing");
+    let path = current_dir()?;
+    info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
+    info!("Listening on {}, data directory: {}", addr, path.display());
+
+    let store = match KvStore::open(&path) {
+        Ok(store) => store,
+        Err(why @ KvsError::Locked { .. }) => {
+            error!("{}", why);
+            exit(1);
+        }
+        Err(why) => return Err(why),
+    };
+    KvsServer::new(store).run(addr)
+}
@@ -1,13 +1,20 @@
+#[macro_use]
 extern crate clap;
 
 use clap::{App, AppSettings, Arg, SubCommand};
-use kvs::KvStore;
+use kvs::{KvStore, KvsError, LogRecord, RecordData, Result};
+use log::LevelFilter;
 use std::env;
 use std::env::current_dir;
+use std::fs::File;
 use std::io;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
 use std::process::exit;
+use std::time::Duration;
 
-fn main() -> Result<(), String> {
+fn main() -> Result<()> {
+    env_logger::builder().filter_level(LevelFilter::Info).init();
     let matches = App::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
         .author(env!("CARGO_PKG_AUTHORS"))
@@ -27,6 +34,12 @@ fn main() -> Result<(), String> {
                         .index(2)
                         .value_name("VALUE")
                         .required(true),
+                )
+                .arg(
+                    Arg::with_name("ttl")
+                        .long("ttl")
+                        .value_name("SECONDS")
+                        .help("Expire the key after the given number of seconds"),
                 ),
         )
         .subcommand(
@@ -47,26 +60,122 @@ fn main() -> Result<(), String> {
                     .required(true),
             ),
         )
+        .subcommand(
+            SubCommand::with_name("scan")
+                .about("List key value pairs whose key starts with given prefix")
+                .arg(
+                    Arg::with_name("prefix")
+                        .index(1)
+                        .value_name("PREFIX")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("keys-only")
+                        .long("keys-only")
+                        .help("Print keys only"),
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .value_name("N")
+                        .help("Print at most N entries"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("cas")
+                .about("Set key to a new value only if it has the expected value")
+                .arg(
+                    Arg::with_name("key")
+                        .index(1)
+                        .value_name("KEY")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("expected")
+                        .long("expected")
+                        .value_name("VALUE")
+                        .help("Value the key must have, the key must not exist if omitted"),
+                )
+                .arg(
+                    Arg::with_name("new")
+                        .long("new")
+                        .value_name("VALUE")
+                        .help("Value to set, the key is removed if omitted"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("set-if-absent")
+                .about("Set key value only if the key does not exist")
+                .arg(
+                    Arg::with_name("key")
+                        .index(1)
+                        .value_name("KEY")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("value")
+                        .index(2)
+                        .value_name("VALUE")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("backup")
+                .about("Write a consistent copy of the storage to given directory")
+                .arg(
+                    Arg::with_name("dest")
+                        .index(1)
+                        .value_name("DEST")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Write key value pairs to stdout as JSON Lines")
+                .arg(
+                    Arg::with_name("prefix")
+                        .long("prefix")
+                        .value_name("PREFIX")
+                        .help("Export keys starting with PREFIX only"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Set key value pairs read as JSON Lines from given file or stdin")
+                .arg(Arg::with_name("file").index(1).value_name("FILE")),
+        )
+        .subcommand(
+            SubCommand::with_name("log")
+                .about("Inspect the log files")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("dump")
+                        .about("Print every record with its location and whether it is live")
+                        .arg(
+                            Arg::with_name("gen")
+                                .long("gen")
+                                .value_name("N")
+                                .help("Print records of generation N only"),
+                        ),
+                ),
+        )
         .get_matches();
 
     match matches.subcommand() {
         ("set", Some(matches)) => {
             let key = matches.value_of("key").expect("key argument missing");
             let value = matches.value_of("value").expect("value argument missing");
-            let path = match current_dir() {
-                io::Result::Ok(x) => x,
-                io::Result::Err(why) => return Result::Err(why.to_string()),
-            };
-            let mut store = KvStore::open(&path)?;
-            store.set(key.to_string(), value.to_string())?;
+            let store = open_store()?;
+            if matches.is_present("ttl") {
+                let ttl = value_t!(matches, "ttl", u64).unwrap_or_else(|e| e.exit());
+                store.set_with_ttl(key.to_string(), value.to_string(), Duration::from_secs(ttl))?;
+            } else {
+                store.set(key.to_string(), value.to_string())?;
+            }
         }
         ("get", Some(matches)) => {
             let key = matches.value_of("key").expect("key argument missing");
-            let path = match current_dir() {
-                io::Result::Ok(x) => x,
-                io::Result::Err(why) => return Result::Err(why.to_string()),
-            };
-            let mut store = KvStore::open(&path)?;
+            let store = KvStore::open_read_only(&current_dir()?)?;
             if let Some(value) = store.get(key.to_string())? {
                 println!("{}", value);
             } else {
@@ -75,20 +184,138 @@ fn main() -> Result<(), String> {
         }
         ("rm", Some(matches)) => {
             let key = matches.value_of("key").expect("key argument missing");
-            let path = match current_dir() {
-                io::Result::Ok(x) => x,
-                io::Result::Err(why) => return Result::Err(why.to_string()),
-            };
-            let mut store = KvStore::open(&path)?;
+            let store = open_store()?;
             match store.remove(key.to_string()) {
-                Ok(_) => {},
-                Err(_) => {
+                Ok(_) => {}
+                Err(KvsError::KeyNotFound) => {
                     println!("Key not found");
                     exit(1);
                 }
+                Err(why) => return Err(why),
             }
         }
+        ("scan", Some(matches)) => {
+            let prefix = matches.value_of("prefix").expect("prefix argument missing");
+            let limit = if matches.is_present("limit") {
+                value_t!(matches, "limit", usize).unwrap_or_else(|e| e.exit())
+            } else {
+                usize::MAX
+            };
+            let store = KvStore::open_read_only(&current_dir()?)?;
+            let iter = store.scan_prefix(prefix.as_bytes())?;
+            if matches.is_present("keys-only") {
+                for key in iter.into_keys().take(limit) {
+                    println!("{}", String::from_utf8_lossy(&key));
+                }
+            } else {
+                for pair in iter.take(limit) {
+                    let (key, value) = pair?;
+                    println!(
+                        "{}\t{}",
+                        String::from_utf8_lossy(&key),
+                        String::from_utf8_lossy(&value)
+                    );
+                }
+            }
+        }
+        ("cas", Some(matches)) => {
+            let key = matches.value_of("key").expect("key argument missing");
+            let expected = matches.value_of("expected").map(str::to_string);
+            let new = matches.value_of("new").map(str::to_string);
+            let store = open_store()?;
+            if !store.compare_and_swap(key.to_string(), expected, new)? {
+                println!("Value does not match");
+                exit(1);
+            }
+        }
+        ("set-if-absent", Some(matches)) => {
+            let key = matches.value_of("key").expect("key argument missing");
+            let value = matches.value_of("value").expect("value argument missing");
+            let store = open_store()?;
+            if !store.set_if_absent(key.to_string(), value.to_string())? {
+                println!("Key already exists");
+                exit(1);
+            }
+        }
+        ("backup", Some(matches)) => {
+            let dest = matches.value_of("dest").expect("dest argument missing");
+            let store = KvStore::open_read_only(&current_dir()?)?;
+            store.checkpoint(Path::new(dest))?;
+        }
+        ("export", Some(matches)) => {
+            let prefix = matches.value_of("prefix").unwrap_or("");
+            let store = KvStore::open_read_only(&current_dir()?)?;
+            let stdout = io::stdout();
+            store.export(prefix.as_bytes(), BufWriter::new(stdout.lock()))?;
+        }
+        ("import", Some(matches)) => {
+            let store = open_store()?;
+            match matches.value_of("file") {
+                Some(file) => store.import(BufReader::new(File::open(file)?))?,
+                None => store.import(io::stdin().lock())?,
+            };
+        }
+        ("log", Some(matches)) => match matches.subcommand() {
+            ("dump", Some(matches)) => {
+                let gen = if matches.is_present("gen") {
+                    Some(value_t!(matches, "gen", u64).unwrap_or_else(|e| e.exit()))
+                } else {
+                    None
+                };
+                for record in KvStore::dump_dir(&current_dir()?, gen)? {
+                    print_record(&record);
+                }
+            }
+            _ => unreachable!(),
+        },
         _ => unreachable!(),
     };
     Ok(())
 }
+
+// opens the store in the current directory for writing,
+// exits with a message if another process has it open,
+// commands which only read open it read-only instead
+fn open_store() -> Result<KvStore> {
+    match KvStore::open(&current_dir()?) {
+        Err(why @ KvsError::Locked { .. }) => {
+            eprintln!("{}", why);
+            exit(1);
+        }
+        result => result,
+    }
+}
+
+// prints one tab separated line: gen, offset, size, live or stale, op and its operands
+fn print_record(record: &LogRecord) {
+    let status = if record.live { "live" } else { "stale" };
+    let data = match &record.data {
+        RecordData::Set {
+            key,
+            value,
+            expires_at: None,
+        } => format!(
+            "set\t{}\t{}",
+            String::from_utf8_lossy(key),
+            String::from_utf8_lossy(value)
+        ),
+        RecordData::Set {
+            key,
+            value,
+            expires_at: Some(expires_at),
+        } => format!(
+            "set\t{}\t{}\texpires_at={}",
+            String::from_utf8_lossy(key),
+            String::from_utf8_lossy(value),
+            expires_at
+        ),
+        RecordData::Remove { key } => format!("rm\t{}", String::from_utf8_lossy(key)),
+        RecordData::BatchBegin { count } => format!("batch-begin\t{}", count),
+        RecordData::BatchCommit => "batch-commit".to_owned(),
+        RecordData::Unreadable { reason } => format!("unreadable\t{}", reason),
+    };
+    println!(
+        "{}\t{}\t{}\t{}\t{}",
+        record.gen, record.pos, record.size, status, data
+    );
+}
@@ -0,0 +1,82 @@
+use crate::error::{KvsError, Result};
+use crate::protocol::{Request, Response};
+use serde::Deserialize;
+use serde_json::de::IoRead;
+use serde_json::Deserializer;
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+
+/// Client talking to a `KvsServer`
+pub struct KvsClient {
+    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl KvsClient {
+    /// Connects to the server listening on the given address
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<KvsClient> {
+        let stream = TcpStream::connect(addr)?;
+        let reader = BufReader::new(stream.try_clone()?);
+        Ok(KvsClient {
+            reader: Deserializer::from_reader(reader),
+            writer: BufWriter::new(stream),
+        })
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.request(&Request::Get { key })
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.request(&Request::Set { key, value })?;
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.request(&Request::Remove { key })?;
+        Ok(())
+    }
+
+    /// Sets `key` to `new` if its current value equals `expected`, atomically on the server
+    pub fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.conditional_request(&Request::CompareAndSwap { key, expected, new })
+    }
+
+    /// Sets the value of a key if it does not exist, atomically on the server
+    pub fn set_if_absent(&mut self, key: String, value: String) -> Result<bool> {
+        self.conditional_request(&Request::SetIfAbsent { key, value })
+    }
+
+    fn request(&mut self, request: &Request) -> Result<Option<String>> {
+        match self.send(request)? {
+            Response::Ok(value) => Ok(value),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn conditional_request(&mut self, request: &Request) -> Result<bool> {
+        match self.send(request)? {
+            Response::Applied(applied) => Ok(applied),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn send(&mut self, request: &Request) -> Result<Response> {
+        serde_json::to_writer(&mut self.writer, request)?;
+        self.writer.flush()?;
+        match Response::deserialize(&mut self.reader)? {
+            Response::KeyNotFound => Err(KvsError::KeyNotFound),
+            Response::Err(msg) => Err(KvsError::Server(msg)),
+            response => Ok(response),
+        }
+    }
+}
+
+fn unexpected(response: Response) -> KvsError {
+    KvsError::Server(format!("Unexpected response: {:?}", response))
+}
@@ -0,0 +1,89 @@
+//! Queue of writes waiting for a group commit
+//!
+//! Concurrent writers push their records to the queue. The first one to find
+//! no leader becomes the leader, takes everything queued so far and appends it
+//! to the log with a single flush, then hands leadership over to the writer
+//! queued next, if any. Every other writer sleeps until its write is done.
+use crate::error::Result;
+use crate::record::Record;
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex};
+
+/// A queued write and the slot to report its outcome to
+pub struct PendingWrite {
+    pub record: Record,
+    pub slot: Arc<CommitSlot>,
+}
+
+#[derive(Default)]
+pub struct CommitQueue {
+    pending: Mutex<Pending>,
+}
+
+#[derive(Default)]
+struct Pending {
+    writes: Vec<PendingWrite>,
+    // whether some writer is leading a group commit right now
+    leading: bool,
+}
+
+impl CommitQueue {
+    /// Queues a record, returns its slot and whether the caller has to lead
+    pub fn push(&self, record: Record) -> (Arc<CommitSlot>, bool) {
+        let slot = Arc::new(CommitSlot::default());
+        let mut pending = self.pending.lock().unwrap();
+        pending.writes.push(PendingWrite {
+            record,
+            slot: Arc::clone(&slot),
+        });
+        let lead = !mem::replace(&mut pending.leading, true);
+        (slot, lead)
+    }
+
+    /// Takes all queued writes, must only be called by the leader
+    pub fn take(&self) -> Vec<PendingWrite> {
+        mem::take(&mut self.pending.lock().unwrap().writes)
+    }
+
+    /// Passes leadership on to the writer queued first, must only be called by the leader
+    pub fn hand_over(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        match pending.writes.first() {
+            Some(next) => next.slot.set(SlotState::Lead),
+            None => pending.leading = false,
+        }
+    }
+}
+
+#[derive(Default)]
+pub enum SlotState {
+    #[default]
+    Waiting,
+    /// The writer has to lead the next group commit
+    Lead,
+    /// The write is done with the given outcome
+    Done(Result<()>),
+}
+
+/// Where a queued writer waits for its turn to lead or the outcome of its write
+#[derive(Default)]
+pub struct CommitSlot {
+    state: Mutex<SlotState>,
+    cond: Condvar,
+}
+
+impl CommitSlot {
+    pub fn set(&self, state: SlotState) {
+        *self.state.lock().unwrap() = state;
+        self.cond.notify_one();
+    }
+
+    /// Blocks until the state is no longer `Waiting` and takes it
+    pub fn wait(&self) -> SlotState {
+        let mut state = self.state.lock().unwrap();
+        while let SlotState::Waiting = *state {
+            state = self.cond.wait(state).unwrap();
+        }
+        mem::take(&mut *state)
+    }
+}
@@ -0,0 +1,28 @@
+use std::time::Duration;
+
+/// Options for opening a `KvStore`
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// When writes reach the operating system and the disk
+    pub durability: Durability,
+}
+
+/// When writes are handed to the operating system and synced to disk
+///
+/// Writes which only reached the operating system survive a crash of the process,
+/// but may be lost on power failure. `KvStore::sync` makes all writes durable
+/// whatever the policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// Writes are buffered in the process until the buffer is full
+    NoFlush,
+    /// Every write is flushed to the operating system before it returns
+    #[default]
+    Flush,
+    /// Every write is flushed and synced to disk before it returns
+    SyncPerWrite,
+    /// Every write is flushed, and synced to disk by a background thread
+    /// at most the given interval later, or when the store is dropped.
+    /// The interval must not be zero.
+    SyncEvery(Duration),
+}
@@ -0,0 +1,77 @@
+use crate::error::Result;
+use std::path::Path;
+
+/// Storage engine interface of kvs
+///
+/// Application code written against this trait can swap the underlying
+/// storage without touching call sites.
+/// Engines are cheap handles which can be cloned and shared between threads.
+pub trait KvsEngine: Clone + Send + Sync + 'static {
+    /// Opens the engine with data stored in the given directory
+    fn open(path: &Path) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Sets the value of a binary key, overwriting any previous value
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    /// Gets the value of a binary key, returns `None` if the key does not exist
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Removes a binary key
+    ///
+    /// Returns `KvsError::KeyNotFound` if the key does not exist.
+    fn remove_bytes(&self, key: &[u8]) -> Result<()>;
+
+    /// Atomically sets a binary key to `new` if its current value equals `expected`
+    ///
+    /// `None` as `expected` means the key must not exist,
+    /// `None` as `new` removes the key. Returns whether the swap happened.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool>;
+
+    /// Sets the value of a key, overwriting any previous value
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Gets the value of a key, returns `None` if the key does not exist
+    ///
+    /// Returns `KvsError::Utf8` if the stored value is not valid UTF-8.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.as_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Removes a key
+    ///
+    /// Returns `KvsError::KeyNotFound` if the key does not exist.
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+
+    /// Atomically sets a key to `new` if its current value equals `expected`
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.as_ref().map(String::as_bytes),
+            new.map(String::into_bytes),
+        )
+    }
+
+    /// Atomically sets the value of a key if it does not exist, returns whether it was set
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+}
@@ -1 +1,115 @@
-pub type Result<T> = std::result::Result<T, String>;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::string::FromUtf8Error;
+
+/// Error type for kvs
+#[derive(Debug)]
+pub enum KvsError {
+    /// IO error
+    Io(io::Error),
+    /// Serialization or deserialization error
+    Serde(serde_json::Error),
+    /// A value read as string is not valid UTF-8
+    Utf8(FromUtf8Error),
+    /// Removing a key which does not exist
+    KeyNotFound,
+    /// A record in the log file at the given generation and offset cannot be decoded
+    CorruptRecord { gen: u64, pos: u64 },
+    /// The log file at the given generation ends in the middle of the record at the given offset
+    IncompleteRecord { gen: u64, pos: u64 },
+    /// The checksum of the record at the given generation and offset does not match its content
+    ChecksumMismatch { gen: u64, pos: u64 },
+    /// The index points to a record which is not a set record
+    UnexpectedRecordType,
+    /// The index points to a generation without an open log file
+    MissingLogFile(u64),
+    /// A key read by a transaction was changed before the transaction committed
+    TransactionConflict,
+    /// The store is opened by another process, with its id if known
+    Locked { pid: Option<u32> },
+    /// Writing to a store opened read-only
+    ReadOnly,
+    /// The options to open the store with are invalid
+    InvalidConfig(String),
+    /// Error message returned by `kvs-server`
+    Server(String),
+}
+
+impl fmt::Display for KvsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KvsError::Io(err) => write!(f, "IO error: {}", err),
+            KvsError::Serde(err) => write!(f, "Serialization error: {}", err),
+            KvsError::Utf8(err) => write!(f, "Invalid UTF-8 value: {}", err),
+            KvsError::KeyNotFound => write!(f, "Key not found"),
+            KvsError::CorruptRecord { gen, pos } => {
+                write!(f, "Corrupt record in gen {} at offset {}", gen, pos)
+            }
+            KvsError::IncompleteRecord { gen, pos } => {
+                write!(f, "Incomplete record in gen {} at offset {}", gen, pos)
+            }
+            KvsError::ChecksumMismatch { gen, pos } => {
+                write!(
+                    f,
+                    "Checksum mismatch of record in gen {} at offset {}",
+                    gen, pos
+                )
+            }
+            KvsError::UnexpectedRecordType => write!(f, "Unexpected record type"),
+            KvsError::MissingLogFile(gen) => write!(f, "Missing log file for gen {}", gen),
+            KvsError::TransactionConflict => {
+                write!(f, "Transaction conflict: a key read has changed since")
+            }
+            KvsError::Locked { pid: Some(pid) } => write!(f, "store is locked by pid {}", pid),
+            KvsError::Locked { pid: None } => write!(f, "store is locked by another process"),
+            KvsError::ReadOnly => write!(f, "store is opened read-only"),
+            KvsError::InvalidConfig(msg) => write!(f, "Invalid config: {}", msg),
+            KvsError::Server(msg) => write!(f, "Server error: {}", msg),
+        }
+    }
+}
+
+impl KvsError {
+    /// Makes a copy of the error to report it to several callers
+    ///
+    /// Wrapped errors are copied as IO errors with the same message.
+    pub(crate) fn duplicate(&self) -> KvsError {
+        match self {
+            KvsError::Io(err) => KvsError::Io(io::Error::new(err.kind(), err.to_string())),
+            KvsError::KeyNotFound => KvsError::KeyNotFound,
+            err => KvsError::Io(io::Error::other(err.to_string())),
+        }
+    }
+}
+
+impl Error for KvsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            KvsError::Io(err) => Some(err),
+            KvsError::Serde(err) => Some(err),
+            KvsError::Utf8(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for KvsError {
+    fn from(err: io::Error) -> KvsError {
+        KvsError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for KvsError {
+    fn from(err: serde_json::Error) -> KvsError {
+        KvsError::Serde(err)
+    }
+}
+
+impl From<FromUtf8Error> for KvsError {
+    fn from(err: FromUtf8Error) -> KvsError {
+        KvsError::Utf8(err)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, KvsError>;
@@ -0,0 +1,86 @@
+//! Export and import of key value pairs as JSON Lines
+//!
+//! Every line holds one pair as `{"key":...,"value":...}`.
+//! Keys and values are written as JSON strings if they are valid UTF-8
+//! and as arrays of bytes otherwise.
+use crate::batch::WriteBatch;
+use crate::error::Result;
+use crate::kv::KvStore;
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+use std::io::{Read, Write};
+
+// number of pairs imported in one write batch
+const IMPORT_BATCH_LEN: usize = 1000;
+
+#[derive(Serialize, Deserialize)]
+struct Pair {
+    key: Data,
+    value: Data,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Data {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl From<Vec<u8>> for Data {
+    fn from(bytes: Vec<u8>) -> Data {
+        match String::from_utf8(bytes) {
+            Ok(text) => Data::Text(text),
+            Err(why) => Data::Bytes(why.into_bytes()),
+        }
+    }
+}
+
+impl From<Data> for Vec<u8> {
+    fn from(data: Data) -> Vec<u8> {
+        match data {
+            Data::Text(text) => text.into_bytes(),
+            Data::Bytes(bytes) => bytes,
+        }
+    }
+}
+
+impl KvStore {
+    /// Writes all live pairs whose key starts with `prefix` to `writer` in key order
+    ///
+    /// Returns the number of pairs written.
+    pub fn export<W: Write>(&self, prefix: &[u8], mut writer: W) -> Result<usize> {
+        let mut count = 0;
+        for pair in self.scan_prefix(prefix)? {
+            let (key, value) = pair?;
+            let pair = Pair {
+                key: key.into(),
+                value: value.into(),
+            };
+            serde_json::to_writer(&mut writer, &pair)?;
+            writer.write_all(b"\n")?;
+            count += 1;
+        }
+        writer.flush()?;
+        Ok(count)
+    }
+
+    /// Sets all pairs read from `reader`, as written by `export`
+    ///
+    /// Pairs are applied in atomic batches, so a failed import may be partially applied.
+    /// Returns the number of pairs imported.
+    pub fn import<R: Read>(&self, reader: R) -> Result<usize> {
+        let mut count = 0;
+        let mut batch = WriteBatch::new();
+        for pair in Deserializer::from_reader(reader).into_iter::<Pair>() {
+            let pair = pair?;
+            batch.set(pair.key, pair.value);
+            count += 1;
+            if batch.len() == IMPORT_BATCH_LEN {
+                self.write(batch)?;
+                batch = WriteBatch::new();
+            }
+        }
+        self.write(batch)?;
+        Ok(count)
+    }
+}
@@ -0,0 +1,140 @@
+//! Hint files for fast startup
+//!
+//! A sealed generation `<gen>.log` may have a `<gen>.hint` file next to it,
+//! listing the location of every record in the log in the same order,
+//! so the index can be rebuilt without reading the values.
+//! Each entry is encoded as
+//!
+//! ```text
+//! +------------+---------+--------+--------+-----------+
+//! | key length | op type | pos    | size   | key bytes |
+//! | u32 LE     | u8      | u64 LE | u64 LE |           |
+//! +------------+---------+--------+--------+-----------+
+//! ```
+//!
+//! with op type `1` for set records and `2` for remove records.
+//! Op type `3` marks set records with an expiry time, their entry is followed by
+//! the expiry time in milliseconds since the UNIX epoch as u64 LE.
+//! The file ends with the CRC-32 of all entries as u32 LE,
+//! a hint file with a wrong checksum is ignored and the log is replayed instead.
+use crate::error::Result;
+use crc32fast::Hasher;
+use log::warn;
+use std::convert::TryInto;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const ENTRY_HEADER_LEN: usize = 21;
+
+const OP_SET: u8 = 1;
+const OP_REMOVE: u8 = 2;
+const OP_SET_EXPIRING: u8 = 3;
+
+/// Location of a record in a log file
+#[derive(Debug)]
+pub struct HintEntry {
+    pub key: Vec<u8>,
+    pub pos: u64,
+    pub size: u64,
+    pub removed: bool,
+    pub expires_at: Option<u64>,
+}
+
+pub fn hint_fname(dirname: &Path, gen: u64) -> PathBuf {
+    dirname.join(format!("{}.hint", gen))
+}
+
+/// Writes the hint file of the given generation
+///
+/// The file is written under a temporary name first,
+/// so a crash never leaves a partial hint file behind.
+pub fn write_hint(dirname: &Path, gen: u64, entries: &[HintEntry]) -> Result<()> {
+    let mut buf = Vec::new();
+    for entry in entries {
+        buf.extend_from_slice(&(entry.key.len() as u32).to_le_bytes());
+        buf.push(match (entry.removed, entry.expires_at) {
+            (true, _) => OP_REMOVE,
+            (false, None) => OP_SET,
+            (false, Some(_)) => OP_SET_EXPIRING,
+        });
+        buf.extend_from_slice(&entry.pos.to_le_bytes());
+        buf.extend_from_slice(&entry.size.to_le_bytes());
+        buf.extend_from_slice(&entry.key);
+        if let (false, Some(expires_at)) = (entry.removed, entry.expires_at) {
+            buf.extend_from_slice(&expires_at.to_le_bytes());
+        }
+    }
+    let mut hasher = Hasher::new();
+    hasher.update(&buf);
+    buf.extend_from_slice(&hasher.finalize().to_le_bytes());
+
+    let tmp_fname = dirname.join(format!("{}.hint.tmp", gen));
+    fs::write(&tmp_fname, &buf)?;
+    fs::rename(&tmp_fname, hint_fname(dirname, gen))?;
+    Ok(())
+}
+
+/// Reads the hint file of the given generation
+///
+/// Returns `None` if there is no hint file or it cannot be decoded.
+pub fn read_hint(dirname: &Path, gen: u64) -> Result<Option<Vec<HintEntry>>> {
+    let buf = match fs::read(hint_fname(dirname, gen)) {
+        Ok(buf) => buf,
+        Err(ref why) if why.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(why) => return Err(why.into()),
+    };
+    let entries = decode_entries(&buf);
+    if entries.is_none() {
+        warn!("Ignore invalid hint file of gen {}", gen);
+    }
+    Ok(entries)
+}
+
+fn decode_entries(buf: &[u8]) -> Option<Vec<HintEntry>> {
+    if buf.len() < 4 {
+        return None;
+    }
+    let (mut buf, crc) = buf.split_at(buf.len() - 4);
+    let mut hasher = Hasher::new();
+    hasher.update(buf);
+    if hasher.finalize() != u32::from_le_bytes(crc.try_into().unwrap()) {
+        return None;
+    }
+
+    let mut entries = Vec::new();
+    while !buf.is_empty() {
+        if buf.len() < ENTRY_HEADER_LEN {
+            return None;
+        }
+        let key_len = u32::from_le_bytes(buf[0..4].try_into().unwrap()) as usize;
+        let op = buf[4];
+        if op != OP_SET && op != OP_REMOVE && op != OP_SET_EXPIRING {
+            return None;
+        }
+        let pos = u64::from_le_bytes(buf[5..13].try_into().unwrap());
+        let size = u64::from_le_bytes(buf[13..21].try_into().unwrap());
+        let rest = &buf[ENTRY_HEADER_LEN..];
+        let entry_len = key_len + if op == OP_SET_EXPIRING { 8 } else { 0 };
+        if rest.len() < entry_len {
+            return None;
+        }
+        let key = rest[..key_len].to_vec();
+        let expires_at = if op == OP_SET_EXPIRING {
+            Some(u64::from_le_bytes(
+                rest[key_len..entry_len].try_into().unwrap(),
+            ))
+        } else {
+            None
+        };
+        entries.push(HintEntry {
+            key,
+            pos,
+            size,
+            removed: op == OP_REMOVE,
+            expires_at,
+        });
+        buf = &rest[entry_len..];
+    }
+    Some(entries)
+}
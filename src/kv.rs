@@ -1,270 +1,1692 @@
-use crate::utils::{BufReaderWithPos, BufWriterWithPos};
-use serde::{Deserialize, Serialize};
-use serde_json::{Deserializer, Serializer};
-use std::collections::HashMap;
-use std::convert::TryInto;
+use crate::batch::WriteBatch;
+use crate::commit_queue::{CommitQueue, PendingWrite, SlotState};
+use crate::config::{Config, Durability};
+use crate::engine::KvsEngine;
+use crate::error::{KvsError, Result};
+use crate::hint::{hint_fname, read_hint, write_hint, HintEntry};
+use crate::record::Record;
+use crate::transaction::Transaction;
+use crate::utils::{now_millis, read_at, BufReaderWithPos, BufWriterWithPos};
+use log::{error, warn};
+use std::collections::btree_map;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::ffi::OsStr;
 use std::fs;
-use std::fs::{File, OpenOptions};
+use std::fs::{File, OpenOptions, TryLockError};
 use std::io;
 use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::{Bound, RangeBounds};
 use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, Weak};
+use std::thread;
+use std::time::Duration;
 
-// value type (filename, file offset, value size)
-#[derive(Debug)]
+// compact log files once this many bytes are taken by stale records
+const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+
+// name of the file locked by the process which has the store open
+const LOCK_FNAME: &str = "LOCK";
+
+// value type (filename, file offset, value size, expiry time, version)
+#[derive(Debug, Clone, Copy)]
 struct Value {
     gen: u64,
     pos: u64,
     size: u64,
+    expires_at: Option<u64>,
+    // changes on every write of the key while the store is open,
+    // values loaded on open all have version 0
+    version: u64,
+}
+
+impl Value {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
 }
 
 // Store key value relation in memory
 // value is of type Value
+//
+// The handle is cheap to clone and can be shared between threads.
+// Reads use positional reads on shared file handles and run in parallel,
+// writes are serialized on the single log writer.
+#[derive(Clone)]
 pub struct KvStore {
-    path: String,
-    index: HashMap<String, Value>,
-    readers: HashMap<u64, BufReaderWithPos<File>>,
-    writer: BufWriterWithPos<fs::File>,
+    path: Arc<PathBuf>,
+    index: Arc<RwLock<BTreeMap<Vec<u8>, Value>>>,
+    readers: Arc<RwLock<HashMap<u64, Arc<File>>>>,
+    // `None` for stores opened read-only
+    writer: Option<Arc<Mutex<LogWriter>>>,
+    // set while the index refers to records still in the write buffer
+    unflushed: Arc<AtomicBool>,
+    // sets and removes waiting to be written by a group commit
+    queue: Arc<CommitQueue>,
+    pins: Arc<Mutex<Pins>>,
+    // keeps other processes from opening the directory until the last handle is dropped
+    _lock: Option<Arc<File>>,
+    // set for stores opened read-only, which may follow a store of another process
+    tail: Option<Arc<Tail>>,
+}
+
+// the single writer appending to the current generation
+struct LogWriter {
+    writer: BufWriterWithPos<File>,
     current_gen: u64,
+    // number of bytes taken by overwritten records and remove tombstones
+    uncompacted: u64,
+    // number of such bytes which triggers compaction, raised after a failed compaction
+    compaction_threshold: u64,
+    // version of the last record written
+    version: u64,
+    durability: Durability,
+    // set while flushed records have not been synced to disk
+    unsynced: bool,
+}
+
+impl Drop for LogWriter {
+    // syncs the writes the background thread of `Durability::SyncEvery` has not synced yet
+    fn drop(&mut self) {
+        if self.unsynced {
+            if let Err(why) = self
+                .writer
+                .flush()
+                .and_then(|()| self.writer.get_ref().sync_data())
+            {
+                error!("Failed to sync log file: {}", why);
+            }
+        }
+    }
+}
+
+// generations pinned by snapshots and stale generations waiting to be removed
+#[derive(Default)]
+struct Pins {
+    // number of snapshots referring to each generation
+    counts: BTreeMap<u64, usize>,
+    stale: BTreeSet<u64>,
 }
 
-// command record to write in log file
-#[derive(Serialize, Deserialize)]
-enum Record {
-    SetRecord { key: String, value: String },
-    RemoveRecord { key: String },
+impl Pins {
+    /// Removes the stale generations older than every pinned one.
+    ///
+    /// Newer stale generations are kept as well, they may hold the remove records
+    /// of keys set in a pinned generation, which would come back on open otherwise.
+    fn remove_unpinned(&mut self, path: &Path) -> Result<()> {
+        let oldest_pinned = self.counts.keys().next().cloned().unwrap_or(u64::MAX);
+        while let Some(&gen) = self.stale.iter().next() {
+            if gen >= oldest_pinned {
+                break;
+            }
+            self.stale.remove(&gen);
+            fs::remove_file(gen_fname(path, gen))?;
+            remove_hint(path, gen)?;
+        }
+        Ok(())
+    }
 }
 
 impl KvStore {
-    pub fn open(tmpdir: &Path) -> Result<KvStore, String> {
-        match fs::create_dir_all(&tmpdir) {
-            io::Result::Ok(_) => (),
-            io::Result::Err(why) => return Result::Err(why.to_string()),
-        };
-        // println!("open diretory: {:?}", tmpdir);
-        let mut index = HashMap::new();
-        let mut readers = HashMap::new();
+    pub fn open(tmpdir: &Path) -> Result<KvStore> {
+        KvStore::open_with_config(tmpdir, Config::default())
+    }
 
-        let gen_list = get_gen_list(tmpdir)?;
-        // println!("gen list: {:?}", &gen_list);
-        for gen in &gen_list {
-            let fname = gen_fname(tmpdir, gen.clone());
-            let mut reader = match new_reader(&fname) {
-                Ok(_x) => _x,
-                Err(why) => return Result::Err(why.to_string()),
-            };
-            match load_file(gen.clone(), &mut index, &mut reader) {
-                Ok(_) => (),
-                Err(why) => return Result::Err(why.to_string()),
-            };
-            readers.insert(gen.clone(), reader);
+    /// Opens the store in the given directory with the given options
+    ///
+    /// Returns `KvsError::InvalidConfig` for `Durability::SyncEvery` with a zero interval.
+    pub fn open_with_config(tmpdir: &Path, config: Config) -> Result<KvStore> {
+        if config.durability == Durability::SyncEvery(Duration::ZERO) {
+            return Err(KvsError::InvalidConfig(
+                "sync interval must not be zero".to_owned(),
+            ));
         }
+        fs::create_dir_all(tmpdir)?;
+        let lock = lock_dir(tmpdir)?;
+        // println!("open diretory: {:?}", tmpdir);
+        let Loaded {
+            index,
+            readers,
+            uncompacted,
+            gen_list,
+            ..
+        } = load_dir(tmpdir, LoadMode::Open)?;
 
         let current_gen = gen_list.last().unwrap_or(&0) + 1;
         // println!("next gen: {}", current_gen);
-        let writer = new_log_file(tmpdir, current_gen, &mut readers)?;
+        let readers = RwLock::new(readers);
+        let writer = new_log_file(tmpdir, current_gen, &readers)?;
+
+        let writer = Arc::new(Mutex::new(LogWriter {
+            writer,
+            current_gen,
+            uncompacted,
+            compaction_threshold: COMPACTION_THRESHOLD,
+            version: 0,
+            durability: config.durability,
+            unsynced: false,
+        }));
+        if let Durability::SyncEvery(interval) = config.durability {
+            let writer = Arc::downgrade(&writer);
+            thread::spawn(move || sync_periodically(writer, interval));
+        }
 
         Ok(KvStore {
-            path: tmpdir.display().to_string(),
-            index: index,
-            readers: readers,
-            writer: writer,
-            current_gen: current_gen,
+            path: Arc::new(tmpdir.to_path_buf()),
+            index: Arc::new(RwLock::new(index)),
+            readers: Arc::new(readers),
+            writer: Some(writer),
+            unflushed: Arc::new(AtomicBool::new(false)),
+            queue: Arc::new(CommitQueue::default()),
+            pins: Arc::new(Mutex::new(Pins::default())),
+            _lock: Some(Arc::new(lock)),
+            tail: None,
         })
     }
 
-    fn write_record(&mut self, record: Record) -> Result<Option<Value>, String> {
-        let pos = self.writer.pos;
-        match serde_json::to_writer(&mut self.writer, &record) {
-            std::result::Result::Ok(_) => (),
-            std::result::Result::Err(why) => return Result::Err(why.to_string()),
+    /// Opens the store in the given directory for reading only
+    ///
+    /// No file is created or modified, and the directory is not locked,
+    /// so the store may be opened by another process at the same time.
+    /// A torn record at the end of the newest generation is skipped instead of truncated.
+    /// The store shows the records written when it was opened until `catch_up` is called.
+    /// All writes fail with `KvsError::ReadOnly`.
+    pub fn open_read_only(path: &Path) -> Result<KvStore> {
+        Ok(KvStore::read_only(
+            path,
+            load_dir(path, LoadMode::ReadOnly)?,
+        ))
+    }
+
+    fn read_only(path: &Path, loaded: Loaded) -> KvStore {
+        let path = Arc::new(path.to_path_buf());
+        let index = Arc::new(RwLock::new(loaded.index));
+        let readers = Arc::new(RwLock::new(loaded.readers));
+        let tail = Arc::new(Tail {
+            path: Arc::clone(&path),
+            index: Arc::clone(&index),
+            readers: Arc::clone(&readers),
+            ends: Mutex::new(loaded.ends),
+        });
+        KvStore {
+            path,
+            index,
+            readers,
+            writer: None,
+            unflushed: Arc::new(AtomicBool::new(false)),
+            queue: Arc::new(CommitQueue::default()),
+            pins: Arc::new(Mutex::new(Pins::default())),
+            _lock: None,
+            tail: Some(tail),
+        }
+    }
+
+    /// Opens the store in the given directory read-only and follows the changes
+    /// made by the process which has it open.
+    ///
+    /// Every `interval` a background thread applies the records appended to the log
+    /// since, see `catch_up`. Writes fail with `KvsError::ReadOnly`.
+    pub fn open_secondary(path: &Path, interval: Duration) -> Result<KvStore> {
+        let store = KvStore::open_read_only(path)?;
+        if let Some(tail) = &store.tail {
+            let weak = Arc::downgrade(tail);
+            thread::spawn(move || follow(weak, interval));
+        }
+        Ok(store)
+    }
+
+    /// Applies the records written to the log files since the store was opened
+    /// or last caught up
+    ///
+    /// Only stores opened read-only or as secondary change, for all others this does nothing.
+    pub fn catch_up(&self) -> Result<()> {
+        match &self.tail {
+            Some(tail) => tail.catch_up(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.as_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+
+    /// Sets the value of a key which expires after `ttl`
+    ///
+    /// Expired keys are treated as absent and dropped on compaction.
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+    }
+
+    /// Gets the value of a binary key
+    pub fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_versioned(key)?.map(|(_, value)| value))
+    }
+
+    /// Gets the value of a binary key together with its version
+    pub(crate) fn get_versioned(&self, key: &[u8]) -> Result<Option<(u64, Vec<u8>)>> {
+        // println!("prepare to get key: {}", &key);
+        let (value, file) = {
+            // hold the index lock while looking up the reader,
+            // so compaction cannot drop the generation in between
+            let index = self.index.read().unwrap();
+            match index.get(key) {
+                Some(&value) if !value.is_expired(now_millis()) => (value, self.reader(value.gen)?),
+                _ => return Ok(None),
+            }
         };
-        match self.writer.flush() {
-            io::Result::Err(why) => return Result::Err(why.to_string()),
-            _ => (),
+        self.ensure_flushed()?;
+        // println!("get key: {}, value: {:?}", &key, &value);
+        read_value(&file, value).map(|v| Some((value.version, v)))
+    }
+
+    /// Returns the version of a binary key, `None` if it does not exist
+    pub(crate) fn version(&self, key: &[u8]) -> Option<u64> {
+        match self.index.read().unwrap().get(key) {
+            Some(value) if !value.is_expired(now_millis()) => Some(value.version),
+            _ => None,
+        }
+    }
+
+    /// Returns an iterator over the key value pairs in the given key range in key order
+    ///
+    /// The keys are taken from the index when the iterator is created,
+    /// values are read from the log files lazily as the iterator advances.
+    /// A range whose start is after its end yields nothing.
+    pub fn range<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<KvIter> {
+        let iter = {
+            let index = self.index.read().unwrap();
+            self.iter_entries(range_entries(&index, range, now_millis()))?
         };
-        Ok(match record {
-            Record::SetRecord { key: _, value: _ } => {
-                // println!(
-                //     "write record: gen: {}, pos: {}, size: {}",
-                //     self.current_gen,
-                //     pos,
-                //     self.writer.pos - pos
-                // );
-                Some(Value {
-                    gen: self.current_gen,
-                    pos: pos,
-                    size: self.writer.pos - pos,
-                })
-            }
-            Record::RemoveRecord { key: _ } => None,
+        self.ensure_flushed()?;
+        Ok(iter)
+    }
+
+    /// Returns an iterator over the key value pairs whose key starts with `prefix`
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<KvIter> {
+        let iter = {
+            let index = self.index.read().unwrap();
+            self.iter_entries(prefix_entries(&index, prefix, now_millis()))?
+        };
+        self.ensure_flushed()?;
+        Ok(iter)
+    }
+
+    /// Takes a consistent read-only view of the store at this point in time
+    ///
+    /// Later writes are not visible through the snapshot, and the log files
+    /// it refers to are kept until it is dropped, even if compaction replaces them.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        let index = self.index.read().unwrap();
+        let mut readers = HashMap::new();
+        for value in index.values() {
+            if let Entry::Vacant(entry) = readers.entry(value.gen) {
+                entry.insert(self.reader(value.gen)?);
+            }
+        }
+        // pin the generations before releasing the index lock,
+        // so compaction cannot remove them in between
+        let mut pins = self.pins.lock().unwrap();
+        for &gen in readers.keys() {
+            *pins.counts.entry(gen).or_insert(0) += 1;
+        }
+        drop(pins);
+        let snapshot = Snapshot {
+            index: index.clone(),
+            readers,
+            now: now_millis(),
+            path: Arc::clone(&self.path),
+            pins: Arc::clone(&self.pins),
+        };
+        drop(index);
+        self.ensure_flushed()?;
+        Ok(snapshot)
+    }
+
+    // must be called with the index locked, so compaction cannot drop
+    // the generations referred to by the entries before their readers are taken
+    fn iter_entries(&self, entries: Vec<(Vec<u8>, Value)>) -> Result<KvIter> {
+        let mut readers = HashMap::new();
+        for (_, value) in &entries {
+            if let Entry::Vacant(entry) = readers.entry(value.gen) {
+                entry.insert(self.reader(value.gen)?);
+            }
+        }
+        Ok(KvIter {
+            entries: entries.into_iter(),
+            readers,
         })
     }
 
-    pub fn get(&mut self, key: String) -> Result<Option<String>, String> {
-        // println!("prepare to get key: {}", &key);
-        match self.index.get(&key) {
-            Some(value) => {
-                // println!("get key: {}, value: {:?}", &key, &value);
-                let gen = value.gen;
-                let v_pos = value.pos;
-                let v_size = value.size;
-                let reader = self.readers.get_mut(&gen).unwrap();
-                match reader.seek(SeekFrom::Start(v_pos)) {
-                    std::result::Result::Ok(_) => (),
-                    std::result::Result::Err(why) => return Result::Err(why.to_string()),
+    /// Sets the value of a binary key
+    pub fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write_set(key, value, None)
+    }
+
+    /// Sets the value of a binary key which expires after `ttl`
+    pub fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expires_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        self.write_set(key, value, Some(expires_at))
+    }
+
+    fn write_set(&self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        self.group_commit(Record::SetRecord {
+            key,
+            value,
+            expires_at,
+        })
+    }
+
+    /// Removes a binary key
+    pub fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        self.group_commit(Record::RemoveRecord { key: key.to_vec() })
+    }
+
+    /// Queues a set or remove record and returns once it is written,
+    /// leading the group commit of all queued records if it is this thread's turn.
+    fn group_commit(&self, record: Record) -> Result<()> {
+        if self.writer.is_none() {
+            return Err(KvsError::ReadOnly);
+        }
+        let (slot, mut lead) = self.queue.push(record);
+        loop {
+            if lead {
+                let writes = self.queue.take();
+                self.commit_group(writes);
+                self.queue.hand_over();
+            }
+            match slot.wait() {
+                SlotState::Done(result) => return result,
+                SlotState::Lead => lead = true,
+                SlotState::Waiting => unreachable!(),
+            }
+        }
+    }
+
+    /// Writes queued records with a single flush and reports the outcome to each writer.
+    ///
+    /// Removes of keys which do not exist at their turn fail without being written.
+    fn commit_group(&self, writes: Vec<PendingWrite>) {
+        let mut writer = match self.lock_writer() {
+            Ok(writer) => writer,
+            Err(why) => {
+                for write in writes {
+                    write.slot.set(SlotState::Done(Err(why.duplicate())));
                 }
-                let mut record_reader = reader.take(v_size);
-                match serde_json::from_reader(&mut record_reader) {
-                    Ok(record) => match record {
-                        Record::SetRecord { key: _, value } => {
-                            // println!("read sucess, value is : {}", &value);
-                            Ok(Some(value))
+                return;
+            }
+        };
+        let mut records = Vec::with_capacity(writes.len());
+        let mut slots = Vec::with_capacity(writes.len());
+        {
+            let index = self.index.read().unwrap();
+            let now = now_millis();
+            // whether keys exist after the records of the group taken so far
+            let mut exists: HashMap<Vec<u8>, bool> = HashMap::new();
+            for write in writes {
+                match &write.record {
+                    Record::SetRecord { key, .. } => {
+                        exists.insert(key.clone(), true);
+                    }
+                    Record::RemoveRecord { key } => {
+                        let found = match exists.get(key) {
+                            Some(&found) => found,
+                            None => index.get(key).is_some_and(|value| !value.is_expired(now)),
+                        };
+                        if !found {
+                            write.slot.set(SlotState::Done(Err(KvsError::KeyNotFound)));
+                            continue;
                         }
-                        _ => Result::Err("Error Reocrd type!".to_owned()),
-                    },
-                    Err(why) => return Result::Err(why.to_string()),
+                        exists.insert(key.clone(), false);
+                    }
+                    _ => (),
+                }
+                records.push(write.record);
+                slots.push(write.slot);
+            }
+        }
+        if records.is_empty() {
+            return;
+        }
+        match self.commit(&mut writer, records) {
+            Ok(()) => {
+                for slot in slots {
+                    slot.set(SlotState::Done(Ok(())));
+                }
+            }
+            Err(why) => {
+                for slot in slots {
+                    slot.set(SlotState::Done(Err(why.duplicate())));
                 }
             }
-            None => Ok(None),
         }
     }
 
-    pub fn set(&mut self, key: String, value: String) -> Result<(), String> {
-        let v = match self.write_record(Record::SetRecord {
-            key: key.clone(),
-            value: value,
-        })? {
-            Some(x) => x,
-            None => return Result::Err("Error Command!".to_owned()),
+    /// Sets `key` to `new` only if its current value equals `expected`
+    ///
+    /// `None` as `expected` means the key must not exist,
+    /// `None` as `new` removes the key. Returns whether the swap happened.
+    pub fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.as_ref().map(String::as_bytes),
+            new.map(String::into_bytes),
+        )
+    }
+
+    /// Sets the value of a key only if it does not exist, returns whether it was set
+    pub fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    /// Sets a binary key to `new` only if its current value equals `expected`
+    pub fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        // holding the writer lock keeps the value unchanged
+        // between the comparison and the write
+        let mut writer = self.lock_writer()?;
+        self.flush_locked(&mut writer)?;
+        let current = match self.index.read().unwrap().get(&key) {
+            Some(&value) if !value.is_expired(now_millis()) => Some(value),
+            _ => None,
+        };
+        let current = match current {
+            Some(value) => Some(read_value(&*self.reader(value.gen)?, value)?),
+            None => None,
         };
-        // println!("prepare to insert key: {}, value: {:?}", &key, &v);
-        self.index.insert(key.clone(), v);
+        if current.as_deref() != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => self.commit(
+                &mut writer,
+                vec![Record::SetRecord {
+                    key,
+                    value,
+                    expires_at: None,
+                }],
+            )?,
+            None if expected.is_some() => {
+                self.commit(&mut writer, vec![Record::RemoveRecord { key }])?
+            }
+            None => (),
+        }
+        Ok(true)
+    }
+
+    /// Sets the value of a binary key only if it does not exist
+    pub fn set_if_absent_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        self.compare_and_swap_bytes(key, None, Some(value))
+    }
+
+    /// Writes a consistent copy of the store into the directory `dest`
+    ///
+    /// Sealed generations are hard-linked, or copied where linking fails,
+    /// e.g. across file systems. The active generation is copied up to the last
+    /// record written when the checkpoint starts, while writes go on.
+    /// Stores opened read-only copy every generation up to the last record
+    /// they have read, so a store running in another process can be backed up.
+    /// The copy can be opened as a store of its own.
+    pub fn checkpoint(&self, dest: &Path) -> Result<()> {
+        fs::create_dir_all(dest)?;
+        if !get_gen_list(dest)?.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already contains log files", dest.display()),
+            )
+            .into());
+        }
+        if let Some(tail) = &self.tail {
+            return self.checkpoint_read_only(tail, dest);
+        }
+        let (active_gen, active, end) = {
+            // the writer lock keeps compaction from removing sealed generations
+            // while they are linked
+            let mut writer = self.lock_writer()?;
+            self.flush_locked(&mut writer)?;
+            let gens: Vec<u64> = self.readers.read().unwrap().keys().cloned().collect();
+            for gen in gens {
+                if gen == writer.current_gen {
+                    continue;
+                }
+                link_or_copy(&gen_fname(&self.path, gen), &gen_fname(dest, gen))?;
+                let hint = hint_fname(&self.path, gen);
+                if hint.is_file() {
+                    link_or_copy(&hint, &hint_fname(dest, gen))?;
+                }
+            }
+            (
+                writer.current_gen,
+                self.reader(writer.current_gen)?,
+                writer.writer.pos,
+            )
+        };
+
+        // the active generation is only appended to, so everything up to `end`
+        // stays the same, and the open handle survives its removal by compaction
+        copy_prefix(&active, end, &gen_fname(dest, active_gen))
+    }
+
+    /// Copies every generation read by a read-only store up to the last record read
+    ///
+    /// The log files may be written and removed by another process meanwhile,
+    /// so they are copied through the open handles, only generations sealed
+    /// with a hint file are linked.
+    fn checkpoint_read_only(&self, tail: &Tail, dest: &Path) -> Result<()> {
+        let files: Vec<(u64, Arc<File>, u64)> = {
+            // keeps `catch_up` from moving on meanwhile
+            let ends = tail.ends.lock().unwrap();
+            let readers = self.readers.read().unwrap();
+            ends.iter()
+                .filter_map(|(&gen, &end)| Some((gen, Arc::clone(readers.get(&gen)?), end)))
+                .collect()
+        };
+        for (gen, file, end) in files {
+            let hint = hint_fname(&self.path, gen);
+            let linked = hint.is_file()
+                && fs::hard_link(gen_fname(&self.path, gen), gen_fname(dest, gen)).is_ok();
+            if !linked {
+                copy_prefix(&file, end, &gen_fname(dest, gen))?;
+            } else if let Err(why) = link_or_copy(&hint, &hint_fname(dest, gen)) {
+                // removed meanwhile, the copy replays the log file instead
+                if !matches!(why, KvsError::Io(ref err) if err.kind() == io::ErrorKind::NotFound) {
+                    return Err(why);
+                }
+            }
+        }
         Ok(())
     }
 
-    pub fn remove(&mut self, key: String) -> Result<(), String> {
-        match self.write_record(Record::RemoveRecord { key: key.clone() })? {
-            Some(_) => return Result::Err("Error Command!".to_owned()),
-            None => (),
+    /// Decodes all records of the given generation, or of every generation if `None`
+    ///
+    /// Each record comes with its location, and set records pointed at
+    /// by the index are flagged as live, all others are superseded.
+    /// Records which cannot be read are reported as `RecordData::Unreadable`.
+    /// Reading goes on after a record whose header is intact, otherwise
+    /// the entry covers the rest of the file.
+    pub fn dump_log(&self, gen: Option<u64>) -> Result<Vec<LogRecord>> {
+        // the writer lock keeps the log files and the index unchanged meanwhile
+        let _writer = match self.writer {
+            Some(_) => {
+                let mut writer = self.lock_writer()?;
+                self.flush_locked(&mut writer)?;
+                Some(writer)
+            }
+            None => None,
         };
+        let mut gens: Vec<u64> = self.readers.read().unwrap().keys().cloned().collect();
+        gens.sort_unstable();
+        if let Some(gen) = gen {
+            if !gens.contains(&gen) {
+                return Err(KvsError::MissingLogFile(gen));
+            }
+            gens = vec![gen];
+        }
 
-        match self.index.remove(&key) {
-            Some(_) => Result::Ok(()),
-            _ => Result::Err(format!("Remove key: {} Error", &key).to_owned()),
+        let index = self.index.read().unwrap();
+        let now = now_millis();
+        let mut records = Vec::new();
+        for gen in gens {
+            let fname = gen_fname(&self.path, gen);
+            let mut reader = new_reader(&fname)?;
+            let mut pos = reader.pos;
+            loop {
+                let record = match Record::read_from(&mut reader, gen, pos) {
+                    Ok(Some(record)) => record,
+                    Ok(None) => break,
+                    Err(
+                        why @ KvsError::IncompleteRecord { .. }
+                        | why @ KvsError::CorruptRecord { .. }
+                        | why @ KvsError::ChecksumMismatch { .. },
+                    ) => {
+                        // a record with an intact header is skipped, otherwise the rest of the file
+                        let size = match why {
+                            KvsError::IncompleteRecord { .. } => None,
+                            _ => Record::size_at(reader.get_ref(), pos)?,
+                        };
+                        records.push(LogRecord {
+                            gen,
+                            pos,
+                            size: size.unwrap_or(fs::metadata(&fname)?.len() - pos),
+                            data: RecordData::Unreadable {
+                                reason: why.to_string(),
+                            },
+                            live: false,
+                        });
+                        match size {
+                            Some(size) => {
+                                pos = reader.seek(SeekFrom::Start(pos + size))?;
+                                continue;
+                            }
+                            None => break,
+                        }
+                    }
+                    Err(why) => return Err(why),
+                };
+                let live = match index.get(record_key(&record)) {
+                    Some(value) => value.gen == gen && value.pos == pos && !value.is_expired(now),
+                    None => false,
+                };
+                records.push(LogRecord {
+                    gen,
+                    pos,
+                    size: reader.pos - pos,
+                    data: record.into(),
+                    live,
+                });
+                pos = reader.pos;
+            }
         }
+        Ok(records)
     }
 
-    pub fn new_log_file(&mut self, gen: u64) -> Result<BufWriterWithPos<File>, String> {
-        new_log_file(
-            &gen_fname(Path::new(&self.path), gen),
-            gen,
-            &mut self.readers,
-        )
+    /// Decodes the records of the store in the given directory like `dump_log`
+    ///
+    /// Records which cannot be read are skipped to tell which records are live,
+    /// so unlike `open_read_only` this works on damaged stores. No file is modified
+    /// and the directory is not locked.
+    pub fn dump_dir(path: &Path, gen: Option<u64>) -> Result<Vec<LogRecord>> {
+        KvStore::read_only(path, load_dir(path, LoadMode::Lenient)?).dump_log(gen)
+    }
+
+    /// Starts an optimistic transaction on the store
+    ///
+    /// See `Transaction` for details.
+    pub fn transaction(&self) -> Transaction {
+        Transaction::new(self.clone())
+    }
+
+    /// Applies all sets and removes of a batch atomically
+    ///
+    /// The batch is framed by begin and commit records in the log,
+    /// a batch without its commit record is ignored when the store is opened.
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        let mut writer = self.lock_writer()?;
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.write_batch(&mut writer, batch)
+    }
+
+    /// Applies a batch if none of the keys read by a transaction changed since.
+    ///
+    /// `reads` maps every key read to the version seen, `None` for absent keys.
+    pub(crate) fn commit_transaction(
+        &self,
+        reads: &HashMap<Vec<u8>, Option<u64>>,
+        batch: WriteBatch,
+    ) -> Result<()> {
+        // holding the writer lock keeps the index unchanged
+        // between checking the versions and applying the batch
+        let mut writer = self.lock_writer()?;
+        for (key, &version) in reads {
+            if self.version(key) != version {
+                return Err(KvsError::TransactionConflict);
+            }
+        }
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.write_batch(&mut writer, batch)
+    }
+
+    // must be called with the writer locked
+    fn write_batch(&self, writer: &mut LogWriter, batch: WriteBatch) -> Result<()> {
+        let mut records = Vec::with_capacity(batch.len() + 2);
+        records.push(Record::BatchBeginRecord {
+            count: batch.len() as u32,
+        });
+        records.extend(batch.into_records());
+        records.push(Record::BatchCommitRecord);
+        self.commit(writer, records)
+    }
+
+    /// Appends records to the log and applies them to the index once they are flushed.
+    ///
+    /// Must be called with the writer locked.
+    fn commit(&self, writer: &mut LogWriter, records: Vec<Record>) -> Result<()> {
+        let mut values = Vec::with_capacity(records.len());
+        for record in &records {
+            values.push(writer.append(record)?);
+        }
+        match writer.durability {
+            Durability::NoFlush => self.unflushed.store(true, Ordering::SeqCst),
+            Durability::Flush => writer.writer.flush()?,
+            Durability::SyncPerWrite => {
+                writer.writer.flush()?;
+                writer.writer.get_ref().sync_data()?;
+            }
+            Durability::SyncEvery(_) => {
+                writer.writer.flush()?;
+                writer.unsynced = true;
+            }
+        }
+
+        let mut index = self.index.write().unwrap();
+        for (record, value) in records.into_iter().zip(values) {
+            match record {
+                Record::SetRecord { key, .. } => {
+                    if let Some(old) = index.insert(key, value) {
+                        writer.uncompacted += old.size;
+                    }
+                }
+                Record::RemoveRecord { key } => {
+                    if let Some(old) = index.remove(&key) {
+                        writer.uncompacted += old.size;
+                    }
+                    // a tombstone is stale as soon as it is written
+                    writer.uncompacted += value.size;
+                }
+                Record::BatchBeginRecord { .. } | Record::BatchCommitRecord => {
+                    writer.uncompacted += value.size;
+                }
+            }
+        }
+        drop(index);
+        // the records are written already, a failed compaction is retried
+        // once another threshold of stale bytes has piled up
+        if writer.uncompacted > writer.compaction_threshold {
+            if let Err(why) = self.compact(writer) {
+                error!("Failed to compact log files: {}", why);
+                writer.compaction_threshold = writer.uncompacted + COMPACTION_THRESHOLD;
+            }
+        }
+        Ok(())
+    }
+
+    /// Flushes all writes and syncs them to disk, whatever the durability policy
+    ///
+    /// Does nothing on stores opened read-only.
+    pub fn sync(&self) -> Result<()> {
+        if self.writer.is_none() {
+            return Ok(());
+        }
+        let mut writer = self.lock_writer()?;
+        self.flush_locked(&mut writer)?;
+        writer.writer.get_ref().sync_data()?;
+        writer.unsynced = false;
+        Ok(())
+    }
+
+    // makes buffered records readable through the log file readers,
+    // must not be called with the index or the writer locked
+    fn ensure_flushed(&self) -> Result<()> {
+        if self.unflushed.load(Ordering::SeqCst) {
+            let mut writer = self.lock_writer()?;
+            self.flush_locked(&mut writer)?;
+        }
+        Ok(())
+    }
+
+    // fails with `KvsError::ReadOnly` if the store is opened read-only
+    fn lock_writer(&self) -> Result<MutexGuard<'_, LogWriter>> {
+        match &self.writer {
+            Some(writer) => Ok(writer.lock().unwrap()),
+            None => Err(KvsError::ReadOnly),
+        }
+    }
+
+    // must be called with the writer locked
+    fn flush_locked(&self, writer: &mut LogWriter) -> Result<()> {
+        if self.unflushed.load(Ordering::SeqCst) {
+            writer.writer.flush()?;
+            self.unflushed.store(false, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    fn reader(&self, gen: u64) -> Result<Arc<File>> {
+        match self.readers.read().unwrap().get(&gen) {
+            Some(file) => Ok(Arc::clone(file)),
+            None => Err(KvsError::MissingLogFile(gen)),
+        }
+    }
+
+    /// Rewrites all live records into a new generation and removes stale log files.
+    ///
+    /// The generation right after the current one holds the compacted records,
+    /// new writes go to the one after it.
+    /// Must be called with the writer locked.
+    fn compact(&self, writer: &mut LogWriter) -> Result<()> {
+        // live records of the current generation are copied through its reader
+        self.flush_locked(writer)?;
+        let compaction_gen = writer.current_gen + 1;
+        writer.writer = new_log_file(&self.path, compaction_gen + 1, &self.readers)?;
+        writer.current_gen = compaction_gen + 1;
+
+        let (entries, expired) = match self.write_compaction(compaction_gen) {
+            Ok(written) => written,
+            Err(why) => {
+                // a partial compaction would end in a torn record which is not the newest
+                self.readers.write().unwrap().remove(&compaction_gen);
+                let removed = remove_hint(&self.path, compaction_gen).and_then(|()| {
+                    match fs::remove_file(gen_fname(&self.path, compaction_gen)) {
+                        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+                        result => Ok(result?),
+                    }
+                });
+                if let Err(err) = removed {
+                    error!("Failed to remove partial compaction: {}", err);
+                }
+                return Err(why);
+            }
+        };
+        let mut index = self.index.write().unwrap();
+        for (key, _) in expired {
+            index.remove(&key);
+        }
+        index.extend(entries);
+        drop(index);
+
+        let mut readers = self.readers.write().unwrap();
+        let stale_gens: Vec<u64> = readers
+            .keys()
+            .filter(|&&gen| gen < compaction_gen)
+            .cloned()
+            .collect();
+        for gen in &stale_gens {
+            readers.remove(gen);
+        }
+        drop(readers);
+        writer.uncompacted = 0;
+        writer.compaction_threshold = COMPACTION_THRESHOLD;
+        // stale generations left behind are removed by the next compaction
+        let mut pins = self.pins.lock().unwrap();
+        pins.stale.extend(stale_gens);
+        pins.remove_unpinned(&self.path)
+    }
+
+    /// Copies all live records to the given generation and writes its hint file
+    ///
+    /// Returns the new locations of the live keys and the expired keys.
+    /// Must be called with the writer locked.
+    #[allow(clippy::type_complexity)]
+    fn write_compaction(
+        &self,
+        compaction_gen: u64,
+    ) -> Result<(Vec<(Vec<u8>, Value)>, Vec<(Vec<u8>, Value)>)> {
+        let mut compaction_writer = new_log_file(&self.path, compaction_gen, &self.readers)?;
+        // writes are blocked by the writer lock, so the index stays the same
+        // while live records are copied and readers keep running meanwhile
+        let now = now_millis();
+        let (mut entries, expired): (Vec<(Vec<u8>, Value)>, Vec<_>) = self
+            .index
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(key, value)| (key.clone(), *value))
+            .partition(|(_, value)| !value.is_expired(now));
+        let mut new_pos = 0;
+        for (_, value) in entries.iter_mut() {
+            let file = self.reader(value.gen)?;
+            let mut buf = vec![0; value.size as usize];
+            read_at(&file, &mut buf, value.pos)?;
+            compaction_writer.write_all(&buf)?;
+            *value = Value {
+                gen: compaction_gen,
+                pos: new_pos,
+                ..*value
+            };
+            new_pos += value.size;
+        }
+        compaction_writer.flush()?;
+        // the compacted records must be on disk before the stale generations go away
+        compaction_writer.get_ref().sync_data()?;
+        let hint_entries: Vec<HintEntry> = entries
+            .iter()
+            .map(|(key, value)| HintEntry {
+                key: key.clone(),
+                pos: value.pos,
+                size: value.size,
+                removed: false,
+                expires_at: value.expires_at,
+            })
+            .collect();
+        write_hint(&self.path, compaction_gen, &hint_entries)?;
+        Ok((entries, expired))
     }
 }
 
-fn new_log_file(
-    path: &Path,
+/// Iterator over key value pairs of a `KvStore` in key order
+pub struct KvIter {
+    entries: std::vec::IntoIter<(Vec<u8>, Value)>,
+    // log files of all generations referred to by the entries,
+    // kept open so compaction does not pull them away
+    readers: HashMap<u64, Arc<File>>,
+}
+
+impl KvIter {
+    /// Turns the iterator into one over the keys only, without reading any value
+    pub fn into_keys(self) -> impl Iterator<Item = Vec<u8>> {
+        self.entries.map(|(key, _)| key)
+    }
+}
+
+impl Iterator for KvIter {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, value) = self.entries.next()?;
+        let file = &self.readers[&value.gen];
+        Some(read_value(file, value).map(|v| (key, v)))
+    }
+}
+
+/// A record decoded from a log file by `KvStore::dump_log`
+#[derive(Debug)]
+pub struct LogRecord {
+    pub gen: u64,
+    pub pos: u64,
+    pub size: u64,
+    pub data: RecordData,
+    /// Whether the index points at this record
+    pub live: bool,
+}
+
+/// Content of a log record
+#[derive(Debug)]
+pub enum RecordData {
+    /// Sets a key, `expires_at` is in milliseconds since the UNIX epoch
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+    },
+    /// Removes a key
+    Remove { key: Vec<u8> },
+    /// Starts a write batch of `count` records
+    BatchBegin { count: u32 },
+    /// Commits the write batch started last
+    BatchCommit,
+    /// Bytes which cannot be read as a record, either a single record with
+    /// an intact header or the rest of the file, left by a crash in the middle
+    /// of a write or by corruption
+    Unreadable { reason: String },
+}
+
+impl From<Record> for RecordData {
+    fn from(record: Record) -> RecordData {
+        match record {
+            Record::SetRecord {
+                key,
+                value,
+                expires_at,
+            } => RecordData::Set {
+                key,
+                value,
+                expires_at,
+            },
+            Record::RemoveRecord { key } => RecordData::Remove { key },
+            Record::BatchBeginRecord { count } => RecordData::BatchBegin { count },
+            Record::BatchCommitRecord => RecordData::BatchCommit,
+        }
+    }
+}
+
+impl LogWriter {
+    /// Appends a record without flushing it, returns its location
+    fn append(&mut self, record: &Record) -> Result<Value> {
+        let pos = self.writer.pos;
+        self.writer.write_all(&record.encode())?;
+        let expires_at = match record {
+            Record::SetRecord { expires_at, .. } => *expires_at,
+            _ => None,
+        };
+        self.version += 1;
+        Ok(Value {
+            gen: self.current_gen,
+            pos,
+            size: self.writer.pos - pos,
+            expires_at,
+            version: self.version,
+        })
+    }
+}
+
+/// Read-only view of a `KvStore` at the time `KvStore::snapshot` was called
+pub struct Snapshot {
+    index: BTreeMap<Vec<u8>, Value>,
+    readers: HashMap<u64, Arc<File>>,
+    // keys expire as seen at the time the snapshot was taken
+    now: u64,
+    path: Arc<PathBuf>,
+    pins: Arc<Mutex<Pins>>,
+}
+
+impl Snapshot {
+    /// Gets the string value of a given string key
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.as_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Gets the value of a binary key
+    pub fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.index.get(key) {
+            Some(&value) if !value.is_expired(self.now) => {
+                read_value(&self.readers[&value.gen], value).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns an iterator over the key value pairs in the given key range in key order
+    pub fn range<R: RangeBounds<Vec<u8>>>(&self, range: R) -> KvIter {
+        KvIter {
+            entries: range_entries(&self.index, range, self.now).into_iter(),
+            readers: self.readers.clone(),
+        }
+    }
+
+    /// Returns an iterator over the key value pairs whose key starts with `prefix`
+    pub fn scan_prefix(&self, prefix: &[u8]) -> KvIter {
+        KvIter {
+            entries: prefix_entries(&self.index, prefix, self.now).into_iter(),
+            readers: self.readers.clone(),
+        }
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let mut pins = self.pins.lock().unwrap();
+        for gen in self.readers.keys() {
+            if let btree_map::Entry::Occupied(mut entry) = pins.counts.entry(*gen) {
+                *entry.get_mut() -= 1;
+                if *entry.get() == 0 {
+                    entry.remove();
+                }
+            }
+        }
+        if let Err(why) = pins.remove_unpinned(&self.path) {
+            warn!("Failed to remove stale log files: {}", why);
+        }
+    }
+}
+
+// replay progress of a store opened read-only
+struct Tail {
+    path: Arc<PathBuf>,
+    index: Arc<RwLock<BTreeMap<Vec<u8>, Value>>>,
+    readers: Arc<RwLock<HashMap<u64, Arc<File>>>>,
+    // offset right after the last record applied of every generation seen
+    ends: Mutex<BTreeMap<u64, u64>>,
+}
+
+// records of one generation read by a secondary store, not applied yet
+struct TailRead {
     gen: u64,
-    readers: &mut HashMap<u64, BufReaderWithPos<File>>,
-) -> Result<BufWriterWithPos<File>, String> {
-    fn _new_log_file(
-        path: &Path,
-        gen: u64,
-        readers: &mut HashMap<u64, BufReaderWithPos<File>>,
-    ) -> io::Result<BufWriterWithPos<File>> {
-        let fname = gen_fname(path, gen);
-        // println!("path: {:?}", &fname);
-        let writer = BufWriterWithPos::new(
-            OpenOptions::new()
-                .create(true)
-                .write(true)
-                .append(true)
-                .open(&fname)?,
-        )?;
-        readers.insert(gen, BufReaderWithPos::new(File::open(&fname)?)?);
-        io::Result::Ok(writer)
+    // log file to read values from, for generations new to the secondary
+    reader: Option<Arc<File>>,
+    entries: Vec<HintEntry>,
+    // offset right after the last record read
+    end: u64,
+}
+
+impl Tail {
+    /// Applies new records of known generations and all records of new ones in order,
+    /// and forgets generations the primary has removed.
+    ///
+    /// Compaction writes all live records to a new generation before it removes
+    /// the old ones, so keys still pointing to a removed generation are gone.
+    fn catch_up(&self) -> Result<()> {
+        let mut ends = self.ends.lock().unwrap();
+        let gen_list = get_gen_list(&self.path)?;
+        let mut reads = Vec::new();
+        for &gen in &gen_list {
+            match self.read(gen, ends.get(&gen).cloned()) {
+                Ok(read) => reads.push(read),
+                // removed since listed, it is forgotten on the next call
+                Err(KvsError::Io(ref why)) if why.kind() == io::ErrorKind::NotFound => (),
+                Err(why) => return Err(why),
+            }
+        }
+
+        // Only a running compaction writes to a generation older than the newest,
+        // and the newest gets no writes until the compaction has finished. Records
+        // of a newer generation are applied only if the older ones were read to their
+        // end and did not grow meanwhile, or the copies written by the compaction would
+        // override newer values on the next call.
+        let mut applied = reads.len();
+        for (i, read) in reads.iter().enumerate().take(reads.len().saturating_sub(1)) {
+            if !self.read_to_end(read)? {
+                applied = i + 1;
+                break;
+            }
+        }
+        let mut index = self.index.write().unwrap();
+        let mut readers = self.readers.write().unwrap();
+        for read in reads.into_iter().take(applied) {
+            if let Some(reader) = read.reader {
+                readers.insert(read.gen, reader);
+            }
+            load_entries(read.gen, read.entries, &mut index);
+            ends.insert(read.gen, read.end);
+        }
+
+        let removed: Vec<u64> = ends
+            .keys()
+            .filter(|gen| gen_list.binary_search(gen).is_err())
+            .cloned()
+            .collect();
+        index.retain(|_, value| !removed.contains(&value.gen));
+        for gen in &removed {
+            readers.remove(gen);
+            ends.remove(gen);
+        }
+        Ok(())
+    }
+
+    /// Reads the records of a generation from offset `start`,
+    /// or from its hint file or its beginning if the generation is new.
+    fn read(&self, gen: u64, start: Option<u64>) -> Result<TailRead> {
+        let fname = gen_fname(&self.path, gen);
+        let reader = match start {
+            Some(_) => None,
+            None => Some(Arc::new(File::open(&fname)?)),
+        };
+        let hint = match start {
+            Some(_) => None,
+            None => read_hint(&self.path, gen)?,
+        };
+        let (entries, end) = match hint {
+            Some(entries) => (entries, fs::metadata(&fname)?.len()),
+            None => {
+                // records may be appended right now, a torn record is read again next time
+                let mut file = File::open(&fname)?;
+                file.seek(SeekFrom::Start(start.unwrap_or(0)))?;
+                let replay = load_file(
+                    gen,
+                    &mut BufReaderWithPos::new(file)?,
+                    Damage::StopAtTornTail,
+                )?;
+                (replay.entries, replay.end)
+            }
+        };
+        Ok(TailRead {
+            gen,
+            reader,
+            entries,
+            end,
+        })
+    }
+
+    /// Whether the generation still ends right after the records read
+    ///
+    /// Generations removed meanwhile were complete, they are only removed after compaction.
+    fn read_to_end(&self, read: &TailRead) -> Result<bool> {
+        match fs::metadata(gen_fname(&self.path, read.gen)) {
+            Ok(metadata) => Ok(metadata.len() == read.end),
+            Err(ref why) if why.kind() == io::ErrorKind::NotFound => Ok(true),
+            Err(why) => Err(why.into()),
+        }
+    }
+}
+
+/// Catches up with the primary every `interval` until the store is dropped.
+fn follow(tail: Weak<Tail>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        let tail = match tail.upgrade() {
+            Some(tail) => tail,
+            None => return,
+        };
+        if let Err(why) = tail.catch_up() {
+            warn!("Failed to catch up with the primary: {}", why);
+        }
+    }
+}
+
+impl KvsEngine for KvStore {
+    fn open(path: &Path) -> Result<KvStore> {
+        KvStore::open(path)
+    }
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        KvStore::set_bytes(self, key, value)
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        KvStore::get_bytes(self, key)
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        KvStore::remove_bytes(self, key)
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        KvStore::compare_and_swap_bytes(self, key, expected, new)
+    }
+}
+
+/// Syncs the writes of the store to disk every `interval`
+/// until the store is dropped.
+fn sync_periodically(writer: Weak<Mutex<LogWriter>>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        let writer = match writer.upgrade() {
+            Some(writer) => writer,
+            None => return,
+        };
+        let mut writer = writer.lock().unwrap();
+        if writer.unsynced {
+            match writer.writer.get_ref().sync_data() {
+                Ok(()) => writer.unsynced = false,
+                Err(why) => error!("Failed to sync log file: {}", why),
+            }
+        }
+    }
+}
+
+/// Reads the value of the set record at the given location
+fn read_value(file: &File, value: Value) -> Result<Vec<u8>> {
+    let mut buf = vec![0; value.size as usize];
+    read_at(file, &mut buf, value.pos)?;
+    match Record::decode(&buf, value.gen, value.pos)? {
+        Record::SetRecord { value, .. } => {
+            // println!("read sucess, value is : {}", &value);
+            Ok(value)
+        }
+        _ => Err(KvsError::UnexpectedRecordType),
     }
-    match _new_log_file(path, gen, readers) {
-        io::Result::Ok(writer) => Result::Ok(writer),
-        io::Result::Err(why) => Result::Err(why.to_string()),
+}
+
+/// Collects the unexpired entries of the index in the given key range
+///
+/// A range whose start is after its end is empty.
+fn range_entries<R: RangeBounds<Vec<u8>>>(
+    index: &BTreeMap<Vec<u8>, Value>,
+    range: R,
+    now: u64,
+) -> Vec<(Vec<u8>, Value)> {
+    // `BTreeMap::range` panics on those
+    let empty = match (range.start_bound(), range.end_bound()) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    };
+    if empty {
+        return Vec::new();
     }
+    index
+        .range(range)
+        .filter(|(_, value)| !value.is_expired(now))
+        .map(|(key, value)| (key.clone(), *value))
+        .collect()
 }
 
-fn load_file(
+/// Collects the unexpired entries of the index whose key starts with `prefix`
+fn prefix_entries(
+    index: &BTreeMap<Vec<u8>, Value>,
+    prefix: &[u8],
+    now: u64,
+) -> Vec<(Vec<u8>, Value)> {
+    index
+        .range(prefix.to_vec()..)
+        .take_while(|(key, _)| key.starts_with(prefix))
+        .filter(|(_, value)| !value.is_expired(now))
+        .map(|(key, value)| (key.clone(), *value))
+        .collect()
+}
+
+/// Returns the key of set and remove records, an empty key for batch records
+fn record_key(record: &Record) -> &[u8] {
+    match record {
+        Record::SetRecord { key, .. } | Record::RemoveRecord { key } => key,
+        Record::BatchBeginRecord { .. } | Record::BatchCommitRecord => &[],
+    }
+}
+
+/// Creates the log file of the given generation and registers a reader for it
+fn new_log_file(
+    path: &Path,
     gen: u64,
-    index: &mut HashMap<String, Value>,
-    reader: &mut BufReaderWithPos<File>,
-) -> Result<u64, String> {
-    let mut current_pos: u64 = 0;
-    let mut stream = Deserializer::from_reader(reader).into_iter::<Record>();
-    while let Some(record) = stream.next() {
-        let next_pos: u64 = stream.byte_offset().try_into().unwrap();
-        match record {
-            Ok(record_t) => match record_t {
-                Record::SetRecord { key: k, value: _ } => {
-                    let v_pos: u64 = current_pos;
-                    let v_size: u64 = next_pos - current_pos;
-                    index.insert(
-                        k,
-                        Value {
+    readers: &RwLock<HashMap<u64, Arc<File>>>,
+) -> Result<BufWriterWithPos<File>> {
+    let fname = gen_fname(path, gen);
+    // println!("path: {:?}", &fname);
+    let writer = BufWriterWithPos::new(OpenOptions::new().create(true).append(true).open(&fname)?)?;
+    readers
+        .write()
+        .unwrap()
+        .insert(gen, Arc::new(File::open(&fname)?));
+    Ok(writer)
+}
+
+// outcome of replaying a log file
+struct Replay {
+    // locations of all complete records in the file, except those of uncommitted batches
+    entries: Vec<HintEntry>,
+    // offset right after the last complete record or committed batch
+    end: u64,
+}
+
+// records of a write batch whose commit record has not been read yet
+struct PendingBatch {
+    pos: u64,
+    count: u32,
+    entries: Vec<HintEntry>,
+}
+
+/// Reads the locations of all records in a log file.
+///
+/// With `allow_torn_tail` an incomplete record at the end of the file,
+/// left behind by a crash during a write, ends the replay instead of failing it.
+/// A write batch without commit record at the end of the file is left out.
+fn load_file(gen: u64, reader: &mut BufReaderWithPos<File>, damage: Damage) -> Result<Replay> {
+    let mut current_pos: u64 = reader.pos;
+    let mut entries = Vec::new();
+    let mut batch: Option<PendingBatch> = None;
+    loop {
+        let record = match Record::read_from(reader, gen, current_pos) {
+            Ok(Some(record)) => record,
+            Ok(None) => break,
+            Err(KvsError::IncompleteRecord { .. }) if damage != Damage::Fail => break,
+            Err(KvsError::ChecksumMismatch { .. }) | Err(KvsError::CorruptRecord { .. })
+                if damage == Damage::Skip =>
+            {
+                match Record::size_at(reader.get_ref(), current_pos)? {
+                    Some(size) => {
+                        current_pos = reader.seek(SeekFrom::Start(current_pos + size))?;
+                        continue;
+                    }
+                    None => break,
+                }
+            }
+            Err(why) => return Err(why),
+        };
+        let next_pos: u64 = reader.pos;
+        let (key, removed, expires_at) = match record {
+            Record::SetRecord {
+                key, expires_at, ..
+            } => (key, false, expires_at),
+            Record::RemoveRecord { key } => (key, true, None),
+            Record::BatchBeginRecord { count } => {
+                // a batch missing its commit record is dropped when skipping damage
+                if batch.is_some() && damage != Damage::Skip {
+                    return Err(KvsError::CorruptRecord {
+                        gen,
+                        pos: current_pos,
+                    });
+                }
+                batch = Some(PendingBatch {
+                    pos: current_pos,
+                    count,
+                    entries: Vec::new(),
+                });
+                current_pos = next_pos;
+                continue;
+            }
+            Record::BatchCommitRecord => {
+                match batch.take() {
+                    Some(pending) if pending.entries.len() == pending.count as usize => {
+                        entries.extend(pending.entries)
+                    }
+                    _ if damage == Damage::Skip => (),
+                    _ => {
+                        return Err(KvsError::CorruptRecord {
                             gen,
-                            pos: v_pos,
-                            size: v_size,
-                        },
+                            pos: current_pos,
+                        })
+                    }
+                }
+                current_pos = next_pos;
+                continue;
+            }
+        };
+        let entry = HintEntry {
+            key,
+            pos: current_pos,
+            size: next_pos - current_pos,
+            removed,
+            expires_at,
+        };
+        match batch.as_mut() {
+            Some(pending) => pending.entries.push(entry),
+            None => entries.push(entry),
+        }
+        current_pos = next_pos;
+    }
+
+    let end = match batch {
+        Some(pending) => pending.pos,
+        None => current_pos,
+    };
+    Ok(Replay { entries, end })
+}
+
+// how `load_file` treats records which cannot be read
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Damage {
+    Fail,
+    // stop at a record torn at the end of the file
+    StopAtTornTail,
+    // skip records whose header is intact, stop at the first one without
+    Skip,
+}
+
+// how `load_dir` treats the files of the directory
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum LoadMode {
+    // truncate a torn tail of the newest generation and write missing hint files
+    Open,
+    // leave all files as they are
+    ReadOnly,
+    // leave all files as they are and skip records which cannot be read
+    Lenient,
+}
+
+// index and log files rebuilt from a directory
+struct Loaded {
+    index: BTreeMap<Vec<u8>, Value>,
+    readers: HashMap<u64, Arc<File>>,
+    uncompacted: u64,
+    gen_list: Vec<u64>,
+    // offset right after the last record applied of every generation
+    ends: BTreeMap<u64, u64>,
+}
+
+/// Rebuilds the index from the log files in the given directory.
+///
+/// In `LoadMode::Open`, a torn tail is truncated and
+/// hint files are written for generations without one.
+fn load_dir(path: &Path, mode: LoadMode) -> Result<Loaded> {
+    let read_only = mode != LoadMode::Open;
+    let mut index = BTreeMap::new();
+    let mut readers = HashMap::new();
+    let mut uncompacted = 0;
+    let mut ends = BTreeMap::new();
+
+    let gen_list = get_gen_list(path)?;
+    // println!("gen list: {:?}", &gen_list);
+    // only the newest generation with data may end in a record torn by a crash
+    let mut tail_gen = None;
+    for &gen in gen_list.iter().rev() {
+        if fs::metadata(gen_fname(path, gen))?.len() > 0 {
+            tail_gen = Some(gen);
+            break;
+        }
+    }
+    for &gen in &gen_list {
+        let fname = gen_fname(path, gen);
+        // all existing generations are sealed once the store writes to a new one,
+        // so replay only those without a hint file and write one for them
+        let entries = match read_hint(path, gen)? {
+            Some(entries) => {
+                ends.insert(gen, fs::metadata(&fname)?.len());
+                entries
+            }
+            None => {
+                let mut reader = new_reader(&fname)?;
+                let damage = match mode {
+                    LoadMode::Lenient => Damage::Skip,
+                    _ if Some(gen) == tail_gen => Damage::StopAtTornTail,
+                    _ => Damage::Fail,
+                };
+                let replay = load_file(gen, &mut reader, damage)?;
+                let len = fs::metadata(&fname)?.len();
+                if replay.end < len && !read_only {
+                    warn!(
+                        "Discard {} bytes of incomplete writes at the end of gen {} from offset {}",
+                        len - replay.end,
+                        gen,
+                        replay.end
                     );
-                    current_pos = next_pos;
+                    OpenOptions::new()
+                        .write(true)
+                        .open(&fname)?
+                        .set_len(replay.end)?;
                 }
-                Record::RemoveRecord { key } => {
-                    index.remove(&key);
+                if !replay.entries.is_empty() && !read_only {
+                    write_hint(path, gen, &replay.entries)?;
                 }
-            },
-            Err(why) => return Result::Err(why.to_string()),
+                ends.insert(gen, replay.end);
+                replay.entries
+            }
+        };
+        uncompacted += load_entries(gen, entries, &mut index);
+        readers.insert(gen, Arc::new(File::open(&fname)?));
+    }
+
+    Ok(Loaded {
+        index,
+        readers,
+        uncompacted,
+        gen_list,
+        ends,
+    })
+}
+
+/// Applies the records of a generation to the index in order.
+///
+/// Expired set records remove the key just like remove records.
+/// Returns the number of bytes taken by stale records,
+/// which can be reclaimed by compaction.
+fn load_entries(gen: u64, entries: Vec<HintEntry>, index: &mut BTreeMap<Vec<u8>, Value>) -> u64 {
+    let now = now_millis();
+    let mut uncompacted: u64 = 0;
+    for entry in entries {
+        let value = Value {
+            gen,
+            pos: entry.pos,
+            size: entry.size,
+            expires_at: entry.expires_at,
+            version: 0,
+        };
+        if entry.removed || value.is_expired(now) {
+            if let Some(old) = index.remove(&entry.key) {
+                uncompacted += old.size;
+            }
+            uncompacted += entry.size;
+        } else if let Some(old) = index.insert(entry.key, value) {
+            uncompacted += old.size;
         }
     }
+    uncompacted
+}
 
-    Ok(current_pos)
+/// Takes the exclusive lock on the `LOCK` file of the directory
+/// and writes the id of this process into it.
+///
+/// Fails with `KvsError::Locked` if another process holds the lock.
+fn lock_dir(dirname: &Path) -> Result<File> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dirname.join(LOCK_FNAME))?;
+    match file.try_lock() {
+        Ok(()) => {
+            file.set_len(0)?;
+            write!(file, "{}", process::id())?;
+            file.flush()?;
+            Ok(file)
+        }
+        Err(TryLockError::WouldBlock) => {
+            let mut content = String::new();
+            let pid = match file.read_to_string(&mut content) {
+                Ok(_) => content.trim().parse().ok(),
+                Err(_) => None,
+            };
+            Err(KvsError::Locked { pid })
+        }
+        Err(TryLockError::Error(why)) => Err(why.into()),
+    }
 }
 
 fn gen_fname(dirname: &Path, gen: u64) -> PathBuf {
     dirname.join(format!("{}.log", gen))
 }
 
-fn new_reader(fname: &Path) -> Result<BufReaderWithPos<File>, String> {
-    let file = match OpenOptions::new().read(true).open(fname) {
-        Err(why) => return Result::Err(why.to_string()),
-        Ok(file) => file,
-    };
-    match BufReaderWithPos::new(file) {
-        io::Result::Ok(x) => Result::Ok(x),
-        io::Result::Err(why) => Result::Err(why.to_string()),
+/// Hard-links `src` to `dest`, copies the file if linking is not possible
+/// Copies the first `end` bytes of `file` to a new file at `dest` and syncs it
+fn copy_prefix(file: &File, end: u64, dest: &Path) -> Result<()> {
+    let mut dest = File::create(dest)?;
+    let mut buf = vec![0; 64 * 1024];
+    let mut pos = 0;
+    while pos < end {
+        let len = (end - pos).min(buf.len() as u64) as usize;
+        read_at(file, &mut buf[..len], pos)?;
+        dest.write_all(&buf[..len])?;
+        pos += len as u64;
     }
+    dest.sync_all()?;
+    Ok(())
 }
 
-fn new_writer(fname: &Path, pos: u64) -> Result<BufWriterWithPos<File>, String> {
-    let file = match OpenOptions::new()
-        .read(true)
-        .append(true)
-        .create(true)
-        .open(fname)
-    {
-        Err(why) => return Result::Err(why.to_string()),
-        Ok(file) => file,
-    };
-    match BufWriterWithPos::new(file) {
-        io::Result::Ok(mut writer) => match &writer.seek(SeekFrom::Start(pos)) {
-            Ok(_) => Result::Ok(writer),
-            Err(why) => Result::Err(why.to_string()),
-        },
-        io::Result::Err(why) => Result::Err(why.to_string()),
+fn link_or_copy(src: &Path, dest: &Path) -> Result<()> {
+    if fs::hard_link(src, dest).is_err() {
+        fs::copy(src, dest)?;
     }
+    Ok(())
+}
+
+fn remove_hint(dirname: &Path, gen: u64) -> Result<()> {
+    match fs::remove_file(hint_fname(dirname, gen)) {
+        Err(ref why) if why.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+fn new_reader(fname: &Path) -> Result<BufReaderWithPos<File>> {
+    let file = OpenOptions::new().read(true).open(fname)?;
+    Ok(BufReaderWithPos::new(file)?)
 }
 
 /// Returns sorted generation numbers in the given directory
-fn get_gen_list(path: &Path) -> Result<Vec<u64>, String> {
-    let mut gen_list: Vec<u64> = fs::read_dir(&path)
-        .unwrap()
+fn get_gen_list(path: &Path) -> Result<Vec<u64>> {
+    let mut gen_list: Vec<u64> = fs::read_dir(path)?
         .flat_map(|res| -> io::Result<_> { Ok(res?.path()) })
         .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
         .flat_map(|path| {
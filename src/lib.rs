@@ -1,9 +1,26 @@
 extern crate serde;
 extern crate serde_json;
 
-pub use error::Result;
-pub use kv::KvStore;
+pub use batch::WriteBatch;
+pub use client::KvsClient;
+pub use config::{Config, Durability};
+pub use engine::KvsEngine;
+pub use error::{KvsError, Result};
+pub use kv::{KvIter, KvStore, LogRecord, RecordData, Snapshot};
+pub use server::KvsServer;
+pub use transaction::Transaction;
 
+mod batch;
+mod client;
+mod commit_queue;
+mod config;
+mod engine;
 mod error;
+mod export;
+mod hint;
 mod kv;
+mod protocol;
+mod record;
+mod server;
+mod transaction;
 mod utils;
@@ -0,0 +1,38 @@
+use serde::{Deserialize, Serialize};
+
+/// Request sent from `kvs-client` to `kvs-server`
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Request {
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    Remove {
+        key: String,
+    },
+    CompareAndSwap {
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    },
+    SetIfAbsent {
+        key: String,
+        value: String,
+    },
+}
+
+/// Response sent back from `kvs-server` for each request
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Response {
+    /// The request succeeded, carrying the value for `Get`
+    Ok(Option<String>),
+    /// Whether a conditional write was applied
+    Applied(bool),
+    /// The key to remove does not exist
+    KeyNotFound,
+    /// The request failed on the server side
+    Err(String),
+}
@@ -0,0 +1,249 @@
+//! On-disk encoding of log records
+//!
+//! Every record in a `<gen>.log` file is a fixed size header followed by the payload:
+//!
+//! ```text
+//! +--------+------------+------------+--------------+---------+-----------+-------------+
+//! | crc    | header crc | key length | value length | op type | key bytes | value bytes |
+//! | u32 LE | u32 LE     | u32 LE     | u32 LE       | u8      |           |             |
+//! +--------+------------+------------+--------------+---------+-----------+-------------+
+//! ```
+//!
+//! The crc is the CRC-32 of everything following it, i.e. the rest of the header
+//! and the payload, and is checked whenever a record is read back.
+//! The header crc is the CRC-32 of the lengths and the op type only. It is checked
+//! before the lengths are trusted, so a corrupted length is told apart from
+//! a record torn by a crash at the end of a file.
+//! The op type is `1` for set records, `2` for remove records and `3` for
+//! set records with an expiry time. Remove records always have an empty value,
+//! the value bytes of expiring set records start with the expiry time
+//! in milliseconds since the UNIX epoch as u64 LE, followed by the actual value.
+//! Records are written back to back without any padding, so the size of
+//! a record is `HEADER_LEN + key length + value length`.
+//!
+//! Op types `4` and `5` frame a write batch: the batch begin record has an empty key
+//! and the number of records in the batch as u32 LE value, the batch commit record
+//! is empty. The records of a batch only take effect once its commit record is written.
+use crate::error::{KvsError, Result};
+use crate::utils::read_at;
+use crc32fast::Hasher;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+
+/// Size of the record header in bytes
+pub const HEADER_LEN: u64 = 17;
+
+const OP_SET: u8 = 1;
+const OP_REMOVE: u8 = 2;
+const OP_SET_EXPIRING: u8 = 3;
+const OP_BATCH_BEGIN: u8 = 4;
+const OP_BATCH_COMMIT: u8 = 5;
+
+// command record to write in log file
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum Record {
+    SetRecord {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        // milliseconds since the UNIX epoch after which the value is gone
+        expires_at: Option<u64>,
+    },
+    RemoveRecord {
+        key: Vec<u8>,
+    },
+    // start of a write batch of `count` set and remove records
+    BatchBeginRecord {
+        count: u32,
+    },
+    // end of a write batch, the batch is applied once this is on disk
+    BatchCommitRecord,
+}
+
+impl Record {
+    /// Encodes the record into its on-disk representation
+    pub fn encode(&self) -> Vec<u8> {
+        let count_buf = match self {
+            Record::BatchBeginRecord { count } => count.to_le_bytes(),
+            _ => [0; 4],
+        };
+        let (op, key, value, expires_at) = match self {
+            Record::SetRecord {
+                key,
+                value,
+                expires_at: None,
+            } => (OP_SET, &key[..], &value[..], None),
+            Record::SetRecord {
+                key,
+                value,
+                expires_at: Some(expires_at),
+            } => (OP_SET_EXPIRING, &key[..], &value[..], Some(expires_at)),
+            Record::RemoveRecord { key } => (OP_REMOVE, &key[..], &[][..], None),
+            Record::BatchBeginRecord { .. } => (OP_BATCH_BEGIN, &[][..], &count_buf[..], None),
+            Record::BatchCommitRecord => (OP_BATCH_COMMIT, &[][..], &[][..], None),
+        };
+        let value_len = value.len() + if expires_at.is_some() { 8 } else { 0 };
+        let mut buf = Vec::with_capacity(HEADER_LEN as usize + key.len() + value_len);
+        buf.extend_from_slice(&[0; 8]);
+        buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&(value_len as u32).to_le_bytes());
+        buf.push(op);
+        let header_crc = checksum(&buf[8..], &[]);
+        buf[4..8].copy_from_slice(&header_crc.to_le_bytes());
+        buf.extend_from_slice(key);
+        if let Some(expires_at) = expires_at {
+            buf.extend_from_slice(&expires_at.to_le_bytes());
+        }
+        buf.extend_from_slice(value);
+        let crc = checksum(&buf[4..HEADER_LEN as usize], &buf[HEADER_LEN as usize..]);
+        buf[..4].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    /// Decodes a whole record read from generation `gen` at offset `pos`
+    pub fn decode(buf: &[u8], gen: u64, pos: u64) -> Result<Record> {
+        let corrupt = || KvsError::CorruptRecord { gen, pos };
+        if (buf.len() as u64) < HEADER_LEN {
+            return Err(corrupt());
+        }
+        let (header_buf, payload) = buf.split_at(HEADER_LEN as usize);
+        let header = Header::decode(header_buf);
+        header.verify_header(header_buf, gen, pos)?;
+        if payload.len() as u64 != header.payload_len() {
+            return Err(corrupt());
+        }
+        header.verify(header_buf, payload, gen, pos)?;
+        header.decode_payload(payload, gen, pos)
+    }
+
+    /// Reads the next record of generation `gen` starting at offset `pos`
+    ///
+    /// Returns `None` when the reader is at the end of the file,
+    /// and `KvsError::IncompleteRecord` when the file ends in the middle of the header
+    /// or in the payload of a record whose header is intact.
+    pub fn read_from<R: Read>(reader: &mut R, gen: u64, pos: u64) -> Result<Option<Record>> {
+        let incomplete = || KvsError::IncompleteRecord { gen, pos };
+        let mut header_buf = [0; HEADER_LEN as usize];
+        match read_full(reader, &mut header_buf)? {
+            0 => return Ok(None),
+            n if n < header_buf.len() => return Err(incomplete()),
+            _ => (),
+        }
+        let header = Header::decode(&header_buf);
+        header.verify_header(&header_buf, gen, pos)?;
+        let mut payload = vec![0; header.payload_len() as usize];
+        if read_full(reader, &mut payload)? < payload.len() {
+            return Err(incomplete());
+        }
+        header.verify(&header_buf, &payload, gen, pos)?;
+        header.decode_payload(&payload, gen, pos).map(Some)
+    }
+
+    /// Returns the size of the record at offset `pos` of `file` if its header is intact
+    ///
+    /// A record with an intact header but a corrupt payload can be skipped this way.
+    pub fn size_at(file: &File, pos: u64) -> io::Result<Option<u64>> {
+        let mut header_buf = [0; HEADER_LEN as usize];
+        match read_at(file, &mut header_buf, pos) {
+            Ok(()) => (),
+            Err(ref why) if why.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(why) => return Err(why),
+        }
+        let header = Header::decode(&header_buf);
+        if checksum(&header_buf[8..], &[]) == header.header_crc {
+            Ok(Some(HEADER_LEN + header.payload_len()))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+struct Header {
+    crc: u32,
+    header_crc: u32,
+    key_len: u32,
+    value_len: u32,
+    op: u8,
+}
+
+impl Header {
+    fn decode(buf: &[u8]) -> Header {
+        Header {
+            crc: u32::from_le_bytes(buf[0..4].try_into().unwrap()),
+            header_crc: u32::from_le_bytes(buf[4..8].try_into().unwrap()),
+            key_len: u32::from_le_bytes(buf[8..12].try_into().unwrap()),
+            value_len: u32::from_le_bytes(buf[12..16].try_into().unwrap()),
+            op: buf[16],
+        }
+    }
+
+    /// Checks the stored header crc against the encoded lengths and op type
+    fn verify_header(&self, header_buf: &[u8], gen: u64, pos: u64) -> Result<()> {
+        if checksum(&header_buf[8..], &[]) == self.header_crc {
+            Ok(())
+        } else {
+            Err(KvsError::ChecksumMismatch { gen, pos })
+        }
+    }
+
+    /// Checks the stored crc against the encoded header and payload
+    fn verify(&self, header_buf: &[u8], payload: &[u8], gen: u64, pos: u64) -> Result<()> {
+        if checksum(&header_buf[4..], payload) == self.crc {
+            Ok(())
+        } else {
+            Err(KvsError::ChecksumMismatch { gen, pos })
+        }
+    }
+
+    fn payload_len(&self) -> u64 {
+        u64::from(self.key_len) + u64::from(self.value_len)
+    }
+
+    fn decode_payload(&self, payload: &[u8], gen: u64, pos: u64) -> Result<Record> {
+        let corrupt = || KvsError::CorruptRecord { gen, pos };
+        let (key, value) = payload.split_at(self.key_len as usize);
+        let key = key.to_vec();
+        match self.op {
+            OP_SET => Ok(Record::SetRecord {
+                key,
+                value: value.to_vec(),
+                expires_at: None,
+            }),
+            OP_SET_EXPIRING if value.len() >= 8 => Ok(Record::SetRecord {
+                key,
+                value: value[8..].to_vec(),
+                expires_at: Some(u64::from_le_bytes(value[..8].try_into().unwrap())),
+            }),
+            OP_REMOVE if value.is_empty() => Ok(Record::RemoveRecord { key }),
+            OP_BATCH_BEGIN if key.is_empty() && value.len() == 4 => Ok(Record::BatchBeginRecord {
+                count: u32::from_le_bytes(value.try_into().unwrap()),
+            }),
+            OP_BATCH_COMMIT if key.is_empty() && value.is_empty() => Ok(Record::BatchCommitRecord),
+            _ => Err(corrupt()),
+        }
+    }
+}
+
+fn checksum(header: &[u8], payload: &[u8]) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(header);
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+/// Reads until `buf` is full or the reader reaches the end,
+/// returns the number of bytes read.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref why) if why.kind() == io::ErrorKind::Interrupted => (),
+            Err(why) => return Err(why),
+        }
+    }
+    Ok(len)
+}
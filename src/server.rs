@@ -0,0 +1,80 @@
+use crate::engine::KvsEngine;
+use crate::error::{KvsError, Result};
+use crate::protocol::{Request, Response};
+use log::{debug, error};
+use serde_json::Deserializer;
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::thread;
+
+/// Server serving requests of `KvsClient` with a storage engine
+pub struct KvsServer<E: KvsEngine> {
+    engine: E,
+}
+
+impl<E: KvsEngine> KvsServer<E> {
+    pub fn new(engine: E) -> KvsServer<E> {
+        KvsServer { engine }
+    }
+
+    /// Listens on the given address and serves each connection on its own thread
+    pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let engine = self.engine.clone();
+                    thread::spawn(move || {
+                        if let Err(why) = serve(engine, stream) {
+                            error!("Error on serving client: {}", why);
+                        }
+                    });
+                }
+                Err(why) => error!("Connection failed: {}", why),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn serve<E: KvsEngine>(engine: E, stream: TcpStream) -> Result<()> {
+    let peer_addr = stream.peer_addr()?;
+    let reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+    let requests = Deserializer::from_reader(reader).into_iter::<Request>();
+
+    for request in requests {
+        let request = request?;
+        debug!("Receive request from {}: {:?}", peer_addr, request);
+        let response = match request {
+            Request::Get { key } => to_response(engine.get(key)),
+            Request::Set { key, value } => to_response(engine.set(key, value).map(|_| None)),
+            Request::Remove { key } => to_response(engine.remove(key).map(|_| None)),
+            Request::CompareAndSwap { key, expected, new } => {
+                to_applied_response(engine.compare_and_swap(key, expected, new))
+            }
+            Request::SetIfAbsent { key, value } => {
+                to_applied_response(engine.set_if_absent(key, value))
+            }
+        };
+        serde_json::to_writer(&mut writer, &response)?;
+        writer.flush()?;
+        debug!("Response sent to {}: {:?}", peer_addr, response);
+    }
+    Ok(())
+}
+
+fn to_response(result: Result<Option<String>>) -> Response {
+    match result {
+        Ok(value) => Response::Ok(value),
+        Err(KvsError::KeyNotFound) => Response::KeyNotFound,
+        Err(why) => Response::Err(why.to_string()),
+    }
+}
+
+fn to_applied_response(result: Result<bool>) -> Response {
+    match result {
+        Ok(applied) => Response::Applied(applied),
+        Err(why) => to_response(Err(why)),
+    }
+}
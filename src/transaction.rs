@@ -0,0 +1,95 @@
+use crate::batch::WriteBatch;
+use crate::error::{KvsError, Result};
+use crate::kv::KvStore;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+
+/// An optimistic transaction on a `KvStore`, created by `KvStore::transaction`
+///
+/// Reads go to the store and remember the version of every key read,
+/// writes are buffered in the transaction and visible to its own reads.
+/// `commit` fails with `KvsError::TransactionConflict` if any key read
+/// has changed in the store since, otherwise all writes are applied atomically.
+/// Dropping a transaction without committing discards its writes.
+pub struct Transaction {
+    store: KvStore,
+    // version of every key read, `None` for keys which did not exist
+    reads: HashMap<Vec<u8>, Option<u64>>,
+    // buffered writes, `None` for removed keys
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl Transaction {
+    pub(crate) fn new(store: KvStore) -> Transaction {
+        Transaction {
+            store,
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Gets the string value of a given string key
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.as_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Sets the value of a string key when the transaction commits
+    pub fn set(&mut self, key: String, value: String) {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Removes a string key when the transaction commits
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+
+    /// Gets the value of a binary key
+    pub fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        let versioned = self.store.get_versioned(key)?;
+        self.reads
+            .entry(key.to_vec())
+            .or_insert_with(|| versioned.as_ref().map(|(version, _)| *version));
+        Ok(versioned.map(|(_, value)| value))
+    }
+
+    /// Sets the value of a binary key when the transaction commits
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.insert(key, Some(value));
+    }
+
+    /// Removes a binary key when the transaction commits
+    ///
+    /// Returns `KvsError::KeyNotFound` if the key does not exist for the transaction.
+    pub fn remove_bytes(&mut self, key: &[u8]) -> Result<()> {
+        let exists = match self.writes.get(key) {
+            Some(value) => value.is_some(),
+            None => match self.reads.entry(key.to_vec()) {
+                Entry::Occupied(entry) => entry.get().is_some(),
+                Entry::Vacant(entry) => entry.insert(self.store.version(key)).is_some(),
+            },
+        };
+        if !exists {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.writes.insert(key.to_vec(), None);
+        Ok(())
+    }
+
+    /// Applies the buffered writes atomically unless a key read has changed since
+    pub fn commit(self) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes {
+            match value {
+                Some(value) => batch.set(key, value),
+                None => batch.remove(key),
+            };
+        }
+        self.store.commit_transaction(&self.reads, batch)
+    }
+}
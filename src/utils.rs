@@ -1,4 +1,6 @@
+use std::fs::File;
 use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::io::{BufReader, BufWriter, SeekFrom, Read, Seek, Write};
 
 
@@ -14,12 +16,16 @@ pub struct BufWriterWithPos<W: Write + Seek> {
 
 impl<R: Read + Seek> BufReaderWithPos<R> {
   pub fn new(mut inner: R) -> io::Result<Self> {
-      let pos = inner.seek(SeekFrom::Current(0))?;
+      let pos = inner.stream_position()?;
       Ok(BufReaderWithPos {
           reader: BufReader::new(inner),
           pos,
       })
   }
+
+  pub fn get_ref(&self) -> &R {
+      self.reader.get_ref()
+  }
 }
 
 impl<R: Read + Seek> Read for BufReaderWithPos<R> {
@@ -39,12 +45,16 @@ impl<R: Read + Seek> Seek for BufReaderWithPos<R> {
 
 impl<W: Write + Seek> BufWriterWithPos<W> {
   pub fn new(mut inner: W) -> io::Result<Self> {
-      let pos = inner.seek(SeekFrom::Current(0))?;
+      let pos = inner.stream_position()?;
       Ok(BufWriterWithPos {
           writer: BufWriter::new(inner),
           pos,
       })
   }
+
+  pub fn get_ref(&self) -> &W {
+      self.writer.get_ref()
+  }
 }
 
 impl<W: Write + Seek> Write for BufWriterWithPos<W> {
@@ -64,4 +74,37 @@ impl<W: Write + Seek> Seek for BufWriterWithPos<W> {
       self.pos = self.writer.seek(pos)?;
       Ok(self.pos)
   }
-}
\ No newline at end of file
+}
+
+/// Reads exactly `buf.len()` bytes from `file` starting at `offset`
+/// without moving the shared file cursor, so it can be called from many threads.
+#[cfg(unix)]
+pub fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+  use std::os::unix::fs::FileExt;
+  file.read_exact_at(buf, offset)
+}
+
+/// Reads exactly `buf.len()` bytes from `file` starting at `offset`
+/// without moving the shared file cursor, so it can be called from many threads.
+#[cfg(windows)]
+pub fn read_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+  use std::os::windows::fs::FileExt;
+  while !buf.is_empty() {
+      match file.seek_read(buf, offset)? {
+          0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+          n => {
+              buf = &mut buf[n..];
+              offset += n as u64;
+          }
+      }
+  }
+  Ok(())
+}
+
+/// Returns the current time in milliseconds since the UNIX epoch
+pub fn now_millis() -> u64 {
+  SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map(|d| d.as_millis() as u64)
+      .unwrap_or(0)
+}
@@ -1,8 +1,16 @@
+// the tests pass argument lists to `Command::args` by reference
+#![allow(clippy::needless_borrows_for_generic_args)]
+
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use kvs::{Config, Durability, KvStore, KvsEngine, KvsError, Result, WriteBatch};
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
+use std::ops::Bound;
 use std::process::Command;
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -64,7 +72,7 @@ fn cli_set() {
 #[test]
 fn cli_get_stored() -> Result<()> {
     let temp_dir = TempDir::new_in("tmp").expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
     drop(store);
@@ -92,7 +100,7 @@ fn cli_get_stored() -> Result<()> {
 fn cli_rm_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     drop(store);
 
@@ -175,11 +183,116 @@ fn cli_invalid_subcommand() {
         .failure();
 }
 
+// `kvs-client` should set, get and remove keys through a running `kvs-server`.
+#[test]
+fn cli_client_server() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4004";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["rm", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["rm", "key1", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set-if-absent", "key1", "value1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set-if-absent", "key1", "value2", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("Key already exists"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "cas",
+            "key1",
+            "--expected",
+            "value2",
+            "--new",
+            "value3",
+            "--addr",
+            addr,
+        ])
+        .assert()
+        .failure()
+        .stderr(contains("Value does not match"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "cas",
+            "key1",
+            "--expected",
+            "value1",
+            "--new",
+            "value3",
+            "--addr",
+            addr,
+        ])
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(eq("value3").trim());
+
+    server.kill().expect("server exited before killed");
+    server.wait().expect("failed to wait on server");
+}
+
 // Should get previously stored value
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -189,7 +302,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -200,7 +313,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -209,7 +322,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -221,14 +334,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -237,63 +350,1321 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
+    match store.remove("key1".to_owned()) {
+        Err(KvsError::KeyNotFound) => (),
+        _ => panic!("expected KeyNotFound error"),
+    }
     Ok(())
 }
 
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
     Ok(())
 }
 
-// Insert data until total size of the directory decreases.
-// Test data correctness after compaction.
+// Should detect records whose content does not match their checksum
 #[test]
-fn compaction() -> Result<()> {
+fn checksum_mismatch() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
 
-    let dir_size = || {
-        let entries = WalkDir::new(temp_dir.path()).into_iter();
-        let len: walkdir::Result<u64> = entries
-            .map(|res| {
-                res.and_then(|entry| entry.metadata())
-                    .map(|metadata| metadata.len())
-            })
-            .sum();
-        len.expect("fail to get directory size")
-    };
+    // flip the last byte of the value in the first record of generation 1
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(temp_dir.path().join("1.log"))?;
+    file.seek(SeekFrom::Start(17 + 4 + 5))?;
+    file.write_all(b"X")?;
+    drop(file);
 
-    let mut current_size = dir_size();
-    for iter in 0..1000 {
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            let value = format!("{}", iter);
-            store.set(key, value)?;
-        }
+    match store.get("key1".to_owned()) {
+        Err(KvsError::ChecksumMismatch { gen: 1, pos: 0 }) => (),
+        other => panic!("expected checksum mismatch, got {:?}", other),
+    }
 
-        let new_size = dir_size();
-        if new_size > current_size {
-            current_size = new_size;
-            continue;
-        }
-        // Compaction triggered
+    drop(store);
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::ChecksumMismatch { gen: 1, pos: 0 }) => (),
+        Err(why) => panic!("expected checksum mismatch, got {:?}", why),
+        Ok(_) => panic!("expected checksum mismatch"),
+    }
+    Ok(())
+}
 
-        drop(store);
-        // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
-        }
-        return Ok(());
+// Should discard a record torn by a crash at the end of the newest generation
+#[test]
+fn truncate_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // append the first bytes of a record header only
+    let log_path = temp_dir.path().join("1.log");
+    let len = std::fs::metadata(&log_path)?.len();
+    let mut file = OpenOptions::new().append(true).open(&log_path)?;
+    file.write_all(&[0x12, 0x34, 0x56, 0x78, 4, 0, 0])?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(std::fs::metadata(&log_path)?.len(), len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// `kvs` should report the incomplete writes it discards when opening the store
+#[test]
+fn cli_truncate_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("1.log"))?;
+    file.write_all(&[0x12, 0x34, 0x56])?;
+    drop(file);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["set", "key2", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(contains(
+            "Discard 3 bytes of incomplete writes at the end of gen 1",
+        ));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    Ok(())
+}
+
+// Should refuse to open a newest generation with a corrupted record length instead of truncating it
+#[test]
+fn corrupt_record_length() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    // make the key length of the first record run past the end of the file
+    let log_path = temp_dir.path().join("1.log");
+    let len = std::fs::metadata(&log_path)?.len();
+    let mut file = OpenOptions::new().write(true).open(&log_path)?;
+    file.seek(SeekFrom::Start(10))?;
+    file.write_all(&[0xff])?;
+    drop(file);
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::ChecksumMismatch { gen: 1, pos: 0 }) => (),
+        Err(why) => panic!("expected checksum mismatch, got {:?}", why),
+        Ok(_) => panic!("expected checksum mismatch"),
     }
+    assert_eq!(std::fs::metadata(&log_path)?.len(), len);
+    Ok(())
+}
 
-    panic!("No compaction detected");
+// Should write hint files for sealed generations and fall back to the log if they are invalid
+#[test]
+fn hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    // reopening seals generation 1
+    let store = KvStore::open(temp_dir.path())?;
+    let hint_path = temp_dir.path().join("1.hint");
+    assert!(hint_path.is_file());
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    std::fs::write(&hint_path, b"garbage")?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Should store arbitrary bytes as keys and values
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let key = vec![0, 159, 146, 150, 255];
+    let value = vec![0xff, 0xfe, 0, 1, 2];
+    store.set_bytes(key.clone(), value.clone())?;
+    assert_eq!(store.get_bytes(&key)?, Some(value.clone()));
+    store.set_bytes(b"key1".to_vec(), value.clone())?;
+    match store.get("key1".to_owned()) {
+        Err(KvsError::Utf8(_)) => (),
+        other => panic!("expected invalid UTF-8 error, got {:?}", other),
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(&key)?, Some(value));
+    store.remove_bytes(&key)?;
+    assert_eq!(store.get_bytes(&key)?, None);
+    Ok(())
+}
+
+// Should treat keys as absent once their TTL has passed
+#[test]
+fn expire_keys_with_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    match store.remove("key1".to_owned()) {
+        Err(KvsError::KeyNotFound) => (),
+        other => panic!("expected KeyNotFound error, got {:?}", other),
+    }
+
+    // Open from disk again, expired keys are skipped on replay
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// `kvs set --ttl <SECONDS>` should set a key which expires.
+#[test]
+fn cli_set_ttl() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--ttl", "1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    thread::sleep(Duration::from_millis(1100));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--ttl", "soon"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// Should iterate over a key range in key order
+#[test]
+fn range_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in (100..210).rev() {
+        store.set(format!("user:{}", i), format!("value{}", i))?;
+    }
+    store.remove("user:150".to_owned())?;
+    store.set("user:101".to_owned(), "updated".to_owned())?;
+
+    let pairs = store
+        .range(b"user:100".to_vec()..b"user:200".to_vec())?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(pairs.len(), 99);
+    assert_eq!(pairs[0], (b"user:100".to_vec(), b"value100".to_vec()));
+    assert_eq!(pairs[1], (b"user:101".to_vec(), b"updated".to_vec()));
+    assert!(pairs.windows(2).all(|w| w[0].0 < w[1].0));
+    assert!(pairs.iter().all(|(key, _)| key != b"user:150"));
+
+    let pairs = store
+        .range(b"user:205".to_vec()..)?
+        .collect::<Result<Vec<_>>>()?;
+    let keys: Vec<_> = pairs.into_iter().map(|(key, _)| key).collect();
+    assert_eq!(
+        keys,
+        vec![
+            b"user:205".to_vec(),
+            b"user:206".to_vec(),
+            b"user:207".to_vec(),
+            b"user:208".to_vec(),
+            b"user:209".to_vec(),
+        ]
+    );
+
+    // inverted and empty ranges yield nothing
+    assert_eq!(store.range(b"z".to_vec()..b"a".to_vec())?.count(), 0);
+    let key = b"user:100".to_vec();
+    let bounds = (Bound::Excluded(key.clone()), Bound::Excluded(key));
+    assert_eq!(store.range(bounds.clone())?.count(), 0);
+    assert_eq!(store.snapshot()?.range(bounds).count(), 0);
+    Ok(())
+}
+
+// Should iterate over the keys starting with a prefix
+#[test]
+fn prefix_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("tenant/1/a".to_owned(), "1a".to_owned())?;
+    store.set("tenant/12/a".to_owned(), "12a".to_owned())?;
+    store.set("tenant/1/b".to_owned(), "1b".to_owned())?;
+    store.set("tenant/2/a".to_owned(), "2a".to_owned())?;
+    store.set("tenant/0".to_owned(), "0".to_owned())?;
+
+    let pairs = store
+        .scan_prefix(b"tenant/1/")?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            (b"tenant/1/a".to_vec(), b"1a".to_vec()),
+            (b"tenant/1/b".to_vec(), b"1b".to_vec()),
+        ]
+    );
+    let keys: Vec<_> = store.scan_prefix(b"tenant/1")?.into_keys().collect();
+    assert_eq!(
+        keys,
+        vec![
+            b"tenant/1/a".to_vec(),
+            b"tenant/1/b".to_vec(),
+            b"tenant/12/a".to_vec(),
+        ]
+    );
+    assert_eq!(store.scan_prefix(b"other")?.count(), 0);
+    Ok(())
+}
+
+// `kvs scan <PREFIX>` should list the matching pairs in key order.
+#[test]
+fn cli_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("tenant/1/b".to_owned(), "1b".to_owned())?;
+    store.set("tenant/1/a".to_owned(), "1a".to_owned())?;
+    store.set("tenant/2/a".to_owned(), "2a".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["scan", "tenant/1/"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("tenant/1/a\t1a\ntenant/1/b\t1b\n"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["scan", "tenant/", "--keys-only", "--limit", "2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("tenant/1/a\ntenant/1/b\n"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["scan"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Ok(())
+}
+
+// Should apply write batches atomically and ignore a batch without commit record on open
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key1", "value1")
+        .set("key2", "value2")
+        .remove("key0");
+    assert_eq!(batch.len(), 3);
+    store.write(batch)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // write a batch to generation 2 and cut off its commit record
+    let log_path = temp_dir.path().join("2.log");
+    let mut batch = WriteBatch::new();
+    batch.set("key1", "changed").set("key3", "value3");
+    store.write(batch)?;
+    drop(store);
+    let len = std::fs::metadata(&log_path)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&log_path)?
+        .set_len(len - 13)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(std::fs::metadata(&log_path)?.len(), 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+// Should apply transaction writes atomically and fail on conflicting changes
+#[test]
+fn optimistic_transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("alice".to_owned(), "10".to_owned())?;
+    store.set("bob".to_owned(), "5".to_owned())?;
+
+    let mut txn = store.transaction();
+    let alice: u32 = txn.get("alice".to_owned())?.unwrap().parse().unwrap();
+    let bob: u32 = txn.get("bob".to_owned())?.unwrap().parse().unwrap();
+    txn.set("alice".to_owned(), (alice - 3).to_string());
+    txn.set("bob".to_owned(), (bob + 3).to_string());
+    assert_eq!(txn.get("alice".to_owned())?, Some("7".to_owned()));
+    assert_eq!(store.get("alice".to_owned())?, Some("10".to_owned()));
+    txn.commit()?;
+    assert_eq!(store.get("alice".to_owned())?, Some("7".to_owned()));
+    assert_eq!(store.get("bob".to_owned())?, Some("8".to_owned()));
+
+    // a key read by the transaction is changed before it commits
+    let mut txn = store.transaction();
+    txn.get("alice".to_owned())?;
+    txn.set("bob".to_owned(), "0".to_owned());
+    store.set("alice".to_owned(), "100".to_owned())?;
+    assert!(matches!(txn.commit(), Err(KvsError::TransactionConflict)));
+    assert_eq!(store.get("bob".to_owned())?, Some("8".to_owned()));
+
+    // a key read as absent is created before the transaction commits
+    let mut txn = store.transaction();
+    assert_eq!(txn.get("carol".to_owned())?, None);
+    txn.set("carol".to_owned(), "1".to_owned());
+    store.set("carol".to_owned(), "2".to_owned())?;
+    assert!(matches!(txn.commit(), Err(KvsError::TransactionConflict)));
+    assert_eq!(store.get("carol".to_owned())?, Some("2".to_owned()));
+
+    // changes to keys the transaction did not read do not conflict
+    let mut txn = store.transaction();
+    txn.remove("carol".to_owned())?;
+    assert!(matches!(
+        txn.remove("dave".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    store.set("bob".to_owned(), "9".to_owned())?;
+    txn.commit()?;
+    assert_eq!(store.get("carol".to_owned())?, None);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("alice".to_owned())?, Some("100".to_owned()));
+    assert_eq!(store.get("bob".to_owned())?, Some("9".to_owned()));
+    assert_eq!(store.get("carol".to_owned())?, None);
+    Ok(())
+}
+
+// Should swap values only when the current value matches
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.set_if_absent("leader".to_owned(), "node1".to_owned())?);
+    assert!(!store.set_if_absent("leader".to_owned(), "node2".to_owned())?);
+    assert_eq!(store.get("leader".to_owned())?, Some("node1".to_owned()));
+
+    assert!(!store.compare_and_swap(
+        "leader".to_owned(),
+        Some("node2".to_owned()),
+        Some("node3".to_owned())
+    )?);
+    assert!(store.compare_and_swap(
+        "leader".to_owned(),
+        Some("node1".to_owned()),
+        Some("node3".to_owned())
+    )?);
+    assert!(!store.compare_and_swap("leader".to_owned(), None, Some("node4".to_owned()))?);
+    assert!(store.compare_and_swap("leader".to_owned(), Some("node3".to_owned()), None)?);
+    assert_eq!(store.get("leader".to_owned())?, None);
+
+    // concurrent increments through compare and swap never lose an update
+    store.set("counter".to_owned(), "0".to_owned())?;
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    loop {
+                        let current = store.get("counter".to_owned())?.unwrap();
+                        let next = (current.parse::<u32>().unwrap() + 1).to_string();
+                        if store.compare_and_swap(
+                            "counter".to_owned(),
+                            Some(current),
+                            Some(next),
+                        )? {
+                            break;
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("200".to_owned()));
+    Ok(())
+}
+
+// `kvs cas` and `kvs set-if-absent` should print why nothing was set and exit with non-zero code
+#[test]
+fn cli_compare_and_swap() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["set-if-absent", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["set-if-absent", "key1", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(eq("Key already exists").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["cas", "key1", "--expected", "value2", "--new", "value3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(eq("Value does not match").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["cas", "key1", "--expected", "value1", "--new", "value3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value3").trim());
+
+    // without an expected value the key must not exist, without a new value it is removed
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["cas", "key1", "--new", "value4"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(eq("Value does not match").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["cas", "key1", "--expected", "value3"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+}
+
+// Should read from a snapshot as of its creation and keep its log files across compaction
+#[test]
+fn read_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "old".to_owned())?;
+    }
+    let snapshot = store.snapshot()?;
+
+    store.remove("key0".to_owned())?;
+    store.set("key10".to_owned(), "new".to_owned())?;
+    // overwrite until compaction replaces generation 1
+    let value = "x".repeat(1024);
+    for iter in 0..2000 {
+        store.set(format!("key{}", iter % 10), value.clone())?;
+    }
+    let log_path = temp_dir.path().join("1.log");
+    assert!(log_path.is_file());
+
+    assert_eq!(snapshot.get("key0".to_owned())?, Some("old".to_owned()));
+    assert_eq!(snapshot.get("key10".to_owned())?, None);
+    let pairs: Vec<(Vec<u8>, Vec<u8>)> = snapshot.scan_prefix(b"key").collect::<Result<_>>()?;
+    assert_eq!(pairs.len(), 10);
+    assert!(pairs.iter().all(|(_, value)| value == b"old"));
+    assert_eq!(store.get("key0".to_owned())?, Some(value.clone()));
+
+    drop(snapshot);
+    assert!(!log_path.exists());
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some(value));
+    assert_eq!(store.get("key10".to_owned())?, Some("new".to_owned()));
+    Ok(())
+}
+
+// Should write a checkpoint which opens with the data as of its creation
+#[test]
+fn checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // generation 1 is sealed now, generation 2 is active
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "changed".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.checkpoint(backup_dir.path())?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    let backup = KvStore::open(backup_dir.path())?;
+    assert_eq!(backup.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(backup.get("key2".to_owned())?, Some("changed".to_owned()));
+    assert_eq!(backup.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(backup.get("key4".to_owned())?, None);
+    drop(backup);
+
+    assert!(store.checkpoint(backup_dir.path()).is_err());
+
+    // from a read-only store while the store is written by another handle
+    let other_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    store.set("key5".to_owned(), "value5".to_owned())?;
+    reader.checkpoint(other_dir.path())?;
+    let backup = KvStore::open(other_dir.path())?;
+    assert_eq!(backup.get("key1".to_owned())?, None);
+    assert_eq!(backup.get("key4".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(backup.get("key5".to_owned())?, None);
+    Ok(())
+}
+
+// `kvs backup <DEST>` should write a copy which `kvs` can read from.
+#[test]
+fn cli_backup() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    // while the store is open in another process
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("backup")
+        .arg(backup_dir.path())
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key1"])
+        .current_dir(&backup_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+}
+
+// Should export live pairs as JSON Lines and import them into another store
+#[test]
+fn export_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("user/1".to_owned(), "alice".to_owned())?;
+    store.set("user/2".to_owned(), "bob".to_owned())?;
+    store.set("group/1".to_owned(), "admins".to_owned())?;
+    store.remove("user/2".to_owned())?;
+    store.set_bytes(b"user/3".to_vec(), vec![0xff, 0x00])?;
+
+    let mut buf = Vec::new();
+    assert_eq!(store.export(b"user/", &mut buf)?, 2);
+    assert_eq!(
+        String::from_utf8_lossy(&buf),
+        "{\"key\":\"user/1\",\"value\":\"alice\"}\n{\"key\":\"user/3\",\"value\":[255,0]}\n"
+    );
+
+    let other = KvStore::open(other_dir.path())?;
+    assert_eq!(other.import(&buf[..])?, 2);
+    assert_eq!(other.get("user/1".to_owned())?, Some("alice".to_owned()));
+    assert_eq!(other.get_bytes(b"user/3")?, Some(vec![0xff, 0x00]));
+    assert_eq!(other.get("group/1".to_owned())?, None);
+
+    assert!(other.import(&b"{\"key\":\"user/4\"}\n"[..]).is_err());
+    Ok(())
+}
+
+// `kvs export` output should load with `kvs import`.
+#[test]
+fn cli_export_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("b".to_owned(), "2".to_owned())?;
+    drop(store);
+
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["export"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "{\"key\":\"a\",\"value\":\"1\"}\n{\"key\":\"b\",\"value\":\"2\"}\n"
+    );
+    let dump_path = other_dir.path().join("dump.jsonl");
+    std::fs::write(&dump_path, &output.stdout)?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("import")
+        .arg(&dump_path)
+        .current_dir(&other_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "b"])
+        .current_dir(&other_dir)
+        .assert()
+        .success()
+        .stdout(eq("2").trim());
+    Ok(())
+}
+
+// `kvs log dump` should print every record with its location and liveness.
+#[test]
+fn cli_log_dump() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "old".to_owned())?;
+    store.set("key1".to_owned(), "new".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key3", "value3");
+    store.write(batch)?;
+
+    let records = store.dump_log(Some(1))?;
+    assert_eq!(records.len(), 7);
+    let live: Vec<bool> = records.iter().map(|record| record.live).collect();
+    assert_eq!(live, [false, true, false, false, false, true, false]);
+    assert_eq!(records[1].pos, records[0].size);
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["log", "dump", "--gen", "1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("1\t0\t24\tstale\tset\tkey1\told\n\
+                    1\t24\t24\tlive\tset\tkey1\tnew\n\
+                    1\t48\t27\tstale\tset\tkey2\tvalue2\n\
+                    1\t75\t21\tstale\trm\tkey2\n\
+                    1\t96\t21\tstale\tbatch-begin\t1\n\
+                    1\t117\t27\tlive\tset\tkey3\tvalue3\n\
+                    1\t144\t17\tstale\tbatch-commit\n"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["log", "dump", "--gen", "9"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    // a torn tail is reported after the records before it
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("1.log"))?;
+    file.write_all(&[0x12, 0x34, 0x56])?;
+    drop(file);
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["log", "dump", "--gen", "1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(
+            "1\t144\t17\tstale\tbatch-commit\n\
+             1\t161\t3\tstale\tunreadable\tIncomplete record in gen 1 at offset 161\n",
+        ));
+    Ok(())
+}
+
+// `kvs log dump` should dump the records around a corrupt one in the middle of a log file
+#[test]
+fn cli_log_dump_corrupt() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    // flip the last byte of the value in the second record
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(temp_dir.path().join("1.log"))?;
+    file.seek(SeekFrom::Start(27 + 17 + 4 + 5))?;
+    file.write_all(b"X")?;
+    drop(file);
+    assert!(KvStore::open_read_only(temp_dir.path()).is_err());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["log", "dump"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("1\t0\t27\tstale\tset\tkey1\tvalue1\n\
+                    1\t27\t27\tstale\tunreadable\t\
+                    Checksum mismatch of record in gen 1 at offset 27\n\
+                    1\t54\t27\tlive\tset\tkey1\tvalue3\n"));
+
+    // a corrupt header hides the rest of the file
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(temp_dir.path().join("1.log"))?;
+    file.seek(SeekFrom::Start(27 + 10))?;
+    file.write_all(&[0xff])?;
+    drop(file);
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["log", "dump"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("1\t0\t27\tlive\tset\tkey1\tvalue1\n\
+                    1\t27\t54\tstale\tunreadable\t\
+                    Checksum mismatch of record in gen 1 at offset 27\n"));
+    Ok(())
+}
+
+// Should keep data readable and persistent under every durability policy
+#[test]
+fn durability_policies() -> Result<()> {
+    let policies = [
+        Durability::NoFlush,
+        Durability::Flush,
+        Durability::SyncPerWrite,
+        Durability::SyncEvery(Duration::from_millis(10)),
+    ];
+    for &durability in &policies {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with_config(temp_dir.path(), Config { durability })?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        assert_eq!(store.scan_prefix(b"key")?.count(), 2);
+        store.sync()?;
+        drop(store);
+
+        let store = KvStore::open_with_config(temp_dir.path(), Config { durability })?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    }
+
+    // without flushing, writes only reach the log file on sync
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config {
+        durability: Durability::NoFlush,
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let log_path = temp_dir.path().join("1.log");
+    assert_eq!(std::fs::metadata(&log_path)?.len(), 0);
+    store.sync()?;
+    assert!(std::fs::metadata(&log_path)?.len() > 0);
+
+    let config = Config {
+        durability: Durability::SyncEvery(Duration::ZERO),
+    };
+    assert!(matches!(
+        KvStore::open_with_config(temp_dir.path(), config),
+        Err(KvsError::InvalidConfig(_))
+    ));
+    Ok(())
+}
+
+// Concurrent sets and removes should all be applied through group commits
+#[test]
+fn group_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config {
+        durability: Durability::SyncPerWrite,
+    };
+    let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    store.set("shared".to_owned(), "value".to_owned())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<bool> {
+                for key_id in 0..50 {
+                    let key = format!("key{}-{}", thread_id, key_id);
+                    store.set(key.clone(), format!("value{}", key_id))?;
+                    if key_id % 2 == 0 {
+                        store.remove(key)?;
+                    }
+                }
+                // only one of the threads removes the shared key
+                match store.remove("shared".to_owned()) {
+                    Ok(()) => Ok(true),
+                    Err(KvsError::KeyNotFound) => Ok(false),
+                    Err(why) => Err(why),
+                }
+            })
+        })
+        .collect();
+    let mut removed = 0;
+    for handle in handles {
+        if handle.join().unwrap()? {
+            removed += 1;
+        }
+    }
+    assert_eq!(removed, 1);
+    drop(store);
+
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.get("shared".to_owned())?, None);
+    for thread_id in 0..8 {
+        for key_id in 0..50 {
+            let expected = if key_id % 2 == 0 {
+                None
+            } else {
+                Some(format!("value{}", key_id))
+            };
+            assert_eq!(store.get(format!("key{}-{}", thread_id, key_id))?, expected);
+        }
+    }
+    Ok(())
+}
+
+// Should refuse to open a store which another process has open
+#[test]
+fn directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(temp_dir.path().join("LOCK").is_file());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["set", "key2", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains(format!(
+            "store is locked by pid {}",
+            std::process::id()
+        )));
+
+    // commands which only read open the store read-only, without creating files
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["scan", "key"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("key1\tvalue1\n"));
+    assert!(!temp_dir.path().join("2.log").exists());
+
+    // the lock is released with the last handle
+    let handle = store.clone();
+    drop(store);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    drop(handle);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    Ok(())
+}
+
+// Should read a store opened read-only without touching its files and reject writes
+#[test]
+fn read_only_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    // the store is still open in this process and the log has a torn tail
+    let log_path = temp_dir.path().join("1.log");
+    let mut file = OpenOptions::new().append(true).open(&log_path)?;
+    file.write_all(&[0x12, 0x34, 0x56])?;
+    drop(file);
+    let list_dir = || {
+        let mut names: Vec<_> = std::fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        names
+    };
+    let files = list_dir();
+    let len = std::fs::metadata(&log_path)?.len();
+
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(reader.scan_prefix(b"key")?.count(), 2);
+    assert!(matches!(
+        reader.set("key3".to_owned(), "value3".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(
+        reader.remove("key1".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(
+        reader.set_if_absent("key3".to_owned(), "value3".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(
+        reader.write(WriteBatch::new()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert_eq!(list_dir(), files);
+    assert_eq!(std::fs::metadata(&log_path)?.len(), len);
+
+    assert!(KvStore::open_read_only(&temp_dir.path().join("missing")).is_err());
+    Ok(())
+}
+
+// Should follow the writes of the primary, including across compaction
+#[test]
+fn secondary_catch_up() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let secondary = KvStore::open_secondary(temp_dir.path(), Duration::from_secs(3600))?;
+    assert_eq!(secondary.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        secondary.set("key3".to_owned(), "value3".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+
+    store.set("key1".to_owned(), "new1".to_owned())?;
+    store.remove("key2".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key3", "value3").set("key4", "value4");
+    store.write(batch)?;
+    assert_eq!(secondary.get("key1".to_owned())?, Some("value1".to_owned()));
+    secondary.catch_up()?;
+    assert_eq!(secondary.get("key1".to_owned())?, Some("new1".to_owned()));
+    assert_eq!(secondary.get("key2".to_owned())?, None);
+    assert_eq!(secondary.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    // a torn record at the end is picked up once complete
+    let log_path = temp_dir.path().join("1.log");
+    let mut file = OpenOptions::new().append(true).open(&log_path)?;
+    file.write_all(&[0x12, 0x34, 0x56])?;
+    secondary.catch_up()?;
+    file.set_len(std::fs::metadata(&log_path)?.len() - 3)?;
+    drop(file);
+    store.set("key5".to_owned(), "value5".to_owned())?;
+    secondary.catch_up()?;
+    assert_eq!(secondary.get("key5".to_owned())?, Some("value5".to_owned()));
+
+    // removed and compacted away before the secondary catches up
+    store.remove("key3".to_owned())?;
+    let value = "x".repeat(1024);
+    for iter in 0..2000 {
+        store.set(format!("key{}", iter % 10 + 10), value.clone())?;
+    }
+    store.set("key1".to_owned(), "new2".to_owned())?;
+    assert!(!log_path.exists());
+    secondary.catch_up()?;
+    assert_eq!(secondary.get("key1".to_owned())?, Some("new2".to_owned()));
+    assert_eq!(secondary.get("key3".to_owned())?, None);
+    assert_eq!(secondary.get("key19".to_owned())?, Some(value));
+    assert_eq!(secondary.scan_prefix(b"key")?.count(), 13);
+    drop(secondary);
+
+    let secondary = KvStore::open_secondary(temp_dir.path(), Duration::from_millis(10))?;
+    store.set("key6".to_owned(), "value6".to_owned())?;
+    for _ in 0..100 {
+        if secondary.get("key6".to_owned())?.is_some() {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("secondary did not catch up");
+}
+
+// Should not apply copies of a running compaction over newer values
+#[test]
+fn secondary_during_compaction() -> Result<()> {
+    // records setting `key1` to `old` and to `new`, both of the same size
+    let scratch_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(scratch_dir.path())?;
+    store.set("key1".to_owned(), "old".to_owned())?;
+    store.set("key1".to_owned(), "new".to_owned())?;
+    drop(store);
+    let records = std::fs::read(scratch_dir.path().join("1.log"))?;
+    let (old_record, new_record) = records.split_at(records.len() / 2);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(temp_dir.path().join("1.log"), old_record)?;
+    let secondary = KvStore::open_secondary(temp_dir.path(), Duration::from_secs(3600))?;
+    assert_eq!(secondary.get("key1".to_owned())?, Some("old".to_owned()));
+
+    // generation 2 is being compacted while generation 3 already has a newer write
+    std::fs::write(temp_dir.path().join("2.log"), &old_record[..5])?;
+    std::fs::write(temp_dir.path().join("3.log"), new_record)?;
+    secondary.catch_up()?;
+    assert_eq!(secondary.get("key1".to_owned())?, Some("old".to_owned()));
+
+    std::fs::write(temp_dir.path().join("2.log"), old_record)?;
+    secondary.catch_up()?;
+    assert_eq!(secondary.get("key1".to_owned())?, Some("new".to_owned()));
+    secondary.catch_up()?;
+    assert_eq!(secondary.get("key1".to_owned())?, Some("new".to_owned()));
+
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("new".to_owned()));
+    Ok(())
+}
+
+fn engine_set_get_remove<E: KvsEngine>(engine: &E) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    match engine.remove("key1".to_owned()) {
+        Err(KvsError::KeyNotFound) => (),
+        _ => panic!("expected KeyNotFound error"),
+    }
+    Ok(())
+}
+
+// Should work through the `KvsEngine` trait
+#[test]
+fn kvs_engine_trait() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = <KvStore as KvsEngine>::open(temp_dir.path())?;
+    engine_set_get_remove(&store)
+}
+
+// A failed compaction should neither fail the write nor be retried on every write
+#[test]
+fn compaction_failure() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    // generation 2 would take the compacted records
+    let compaction_path = temp_dir.path().join("2.log");
+    std::fs::create_dir(&compaction_path)?;
+
+    let value = "x".repeat(1024);
+    for iter in 0..1800 {
+        store.set(format!("key{}", iter % 10), format!("{}{}", value, iter))?;
+    }
+    assert!(temp_dir.path().join("1.log").is_file());
+    assert!(temp_dir.path().join("3.log").is_file());
+    assert!(!temp_dir.path().join("4.log").exists());
+
+    std::fs::remove_dir(&compaction_path)?;
+    for iter in 1800..2600 {
+        store.set(format!("key{}", iter % 10), format!("{}{}", value, iter))?;
+    }
+    assert!(!temp_dir.path().join("1.log").exists());
+    assert!(temp_dir.path().join("4.log").is_file());
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("{}{}", value, 2590 + key_id))
+        );
+    }
+    Ok(())
+}
+
+// Insert data until total size of the directory decreases.
+// Test data correctness after compaction.
+#[test]
+fn compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let dir_size = || {
+        let entries = WalkDir::new(temp_dir.path()).into_iter();
+        let len: walkdir::Result<u64> = entries
+            .map(|res| {
+                res.and_then(|entry| entry.metadata())
+                    .map(|metadata| metadata.len())
+            })
+            .sum();
+        len.expect("fail to get directory size")
+    };
+
+    let mut current_size = dir_size();
+    for iter in 0..1000 {
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            let value = format!("{}", iter);
+            store.set(key, value)?;
+        }
+
+        let new_size = dir_size();
+        if new_size > current_size {
+            current_size = new_size;
+            continue;
+        }
+        // Compaction triggered
+
+        drop(store);
+        // reopen and check content
+        let store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+        }
+        return Ok(());
+    }
+
+    panic!("No compaction detected");
+}
+
+// Clones of a store should be usable from many threads at once.
+#[test]
+fn concurrent_set_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..200 {
+                    let key = format!("key{}_{}", thread_id, i);
+                    store.set(key.clone(), format!("value{}", i))?;
+                    assert_eq!(store.get(key)?, Some(format!("value{}", i)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for thread_id in 0..8 {
+        for i in 0..200 {
+            let key = format!("key{}_{}", thread_id, i);
+            assert_eq!(store.get(key)?, Some(format!("value{}", i)));
+        }
+    }
+    Ok(())
 }